@@ -0,0 +1,102 @@
+// Compares how many bytes snapshots take with each encoding, for a game of
+// ten long snakes. Run with `cargo run --example wire_sizes` in libplen
+use libplen::compact::CompactDelta;
+use libplen::constants::WINDOW_SIZE;
+use libplen::delta::GameStateDelta;
+use libplen::food::Food;
+use libplen::gamestate::GameState;
+use libplen::math::{vec2, Vec2};
+use libplen::messages::{
+    encode_server_message, CompactSnapshot, ServerMessage, Snapshot, FEATURE_COMPACT_STATE,
+    FEATURE_COMPRESSION,
+};
+use libplen::player::Player;
+use libplen::snake::SnakeSegment;
+
+const PLAYERS: u64 = 10;
+const SEGMENTS: usize = 400;
+const FOOD: usize = 500;
+// About how far a snake moves in a tick
+const STEP: f32 = 4.;
+
+fn wrap(position: Vec2) -> Vec2 {
+    vec2(position.x.rem_euclid(WINDOW_SIZE), position.y.rem_euclid(WINDOW_SIZE))
+}
+
+fn game() -> GameState {
+    let mut state = GameState::new();
+    for id in 0..PLAYERS {
+        let mut player = Player::new(id, format!("spelare {}", id));
+        let mut position = vec2(id as f32 * 80., 400.);
+        let mut angle = id as f32;
+        // Snakes wind around rather than going straight
+        player.snake.segments = (0..SEGMENTS)
+            .map(|i| {
+                angle += (i as f32 * 0.05).sin() * 0.1;
+                position = wrap(position + Vec2::from_direction(angle, STEP));
+                SnakeSegment { position, angle, cuttable: i > 10 }
+            })
+            .collect();
+        state.players.push(player);
+    }
+    state.food = (0..FOOD)
+        .map(|i| Food::new(vec2((i * 37 % 800) as f32, (i * 91 % 800) as f32)))
+        .collect();
+    state
+}
+
+// What the game looks like a tick later. Every snake grows a new head and
+// loses its tail, and all food moves
+fn next_tick(state: &GameState) -> GameState {
+    let mut next = state.clone();
+    for player in &mut next.players {
+        let head = player.snake.segments[0].clone();
+        let angle = head.angle + 0.05;
+        let position = wrap(head.position + Vec2::from_direction(angle, STEP));
+        player.snake.segments.insert(0, SnakeSegment { position, angle, cuttable: true });
+        player.snake.segments.pop();
+    }
+    for food in &mut next.food {
+        food.position = wrap(food.position + food.velocity * 0.01);
+    }
+    next
+}
+
+fn sizes(name: &str, delta: &GameStateDelta) {
+    let plain = || ServerMessage::Snapshot(Snapshot {
+        id: 1,
+        server_time: 0.,
+        baseline: None,
+        last_processed_input: 0,
+        delta: delta.clone(),
+    });
+    let compact = || ServerMessage::CompactSnapshot(CompactSnapshot {
+        id: 1,
+        server_time: 0.,
+        baseline: None,
+        last_processed_input: 0,
+        delta: CompactDelta::new(delta),
+    });
+    let size = |message: ServerMessage, features| {
+        encode_server_message(&message, features).unwrap().len()
+    };
+
+    let original = size(plain(), 0);
+    println!("{}", name);
+    for (encoding, bytes) in [
+        ("plain", original),
+        ("compact", size(compact(), FEATURE_COMPACT_STATE)),
+        ("plain, deflated", size(plain(), FEATURE_COMPRESSION)),
+        ("compact, deflated", size(compact(), FEATURE_COMPACT_STATE | FEATURE_COMPRESSION)),
+    ] {
+        let share = 100. * bytes as f32 / original as f32;
+        println!("    {:<20} {:>8} bytes {:>6.1}%", encoding, bytes, share);
+    }
+}
+
+fn main() {
+    let state = game();
+    println!("{} players with {} segments each, {} food\n", PLAYERS, SEGMENTS, FOOD);
+    sizes("full snapshot", &GameStateDelta::between(&GameState::new(), &state));
+    sizes("delta after one tick", &GameStateDelta::between(&state, &next_tick(&state)));
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+// Only this many of the latest samples are kept, so that the estimate
+// follows a clock that drifts
+const SAMPLES: usize = 8;
+
+/**
+ *  Estimates the server clock from clock sync exchanges. The server reads
+ *  its clock somewhere between our request and its reply, and taking it to
+ *  be halfway is off by at most half the round trip. The sample with the
+ *  shortest round trip is therefore the one trusted.
+ */
+pub struct ClockSync {
+    // Round trip and how far ahead of ours the server clock was, in seconds
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockSync {
+    pub fn new() -> ClockSync {
+        ClockSync { samples: VecDeque::new() }
+    }
+
+    // Sent and received are times on our clock, in seconds
+    pub fn add_sample(&mut self, sent: f64, server_time: f64, received: f64) {
+        let round_trip = received - sent;
+        // Not a reply to anything we sent
+        if !(round_trip >= 0.) {
+            return;
+        }
+        self.samples.push_back((round_trip, server_time + round_trip / 2. - received));
+        while self.samples.len() > SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn offset(&self) -> Option<f64> {
+        self.samples.iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, offset)| *offset)
+    }
+
+    // What the server clock reads when ours reads local
+    pub fn server_time(&self, local: f64) -> Option<f64> {
+        self.offset().map(|offset| local + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A server whose clock is ahead of ours, on a connection where the way
+    // there and back take different amounts of time
+    fn exchange(clock: &mut ClockSync, sent: f64, there: f64, back: f64) {
+        const SERVER_AHEAD: f64 = 1000.;
+        let server_time = sent + there + SERVER_AHEAD;
+        clock.add_sample(sent, server_time, sent + there + back);
+    }
+
+    #[test]
+    fn the_shortest_round_trip_wins() {
+        let mut clock = ClockSync::new();
+        assert_eq!(clock.server_time(5.), None);
+
+        exchange(&mut clock, 0., 0.3, 0.1);
+        // Off by half the difference between the ways there and back
+        assert!((clock.offset().unwrap() - 1000.1).abs() < 1e-9);
+        exchange(&mut clock, 1., 0.02, 0.02);
+        exchange(&mut clock, 2., 0.5, 0.05);
+        assert!((clock.server_time(10.).unwrap() - 1010.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn old_samples_are_forgotten() {
+        let mut clock = ClockSync::new();
+        exchange(&mut clock, 0., 0.01, 0.01);
+        for i in 0..SAMPLES {
+            exchange(&mut clock, 1. + i as f64, 0.2, 0.1);
+        }
+        assert!((clock.offset().unwrap() - 1000.05).abs() < 1e-9);
+
+        // Replies to requests from the future are nonsense
+        clock.add_sample(100., 0., 99.);
+        assert!((clock.offset().unwrap() - 1000.05).abs() < 1e-9);
+    }
+}
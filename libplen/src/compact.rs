@@ -0,0 +1,197 @@
+use std::f32::consts::TAU;
+use std::mem;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::constants::WINDOW_SIZE;
+use crate::delta::GameStateDelta;
+use crate::food::{Food, FoodType};
+use crate::math::{vec2, Vec2};
+
+// Positions are sent as fractions of the arena and angles as fractions of a
+// full turn, both in this many steps. That is far finer than anything drawn
+const STEPS: f32 = 65536.;
+
+fn quantize(value: f32, range: f32) -> u16 {
+    (value / range * STEPS).round().rem_euclid(STEPS) as u16
+}
+
+fn restore(steps: u16, range: f32) -> f32 {
+    steps as f32 / STEPS * range
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct QuantizedPosition {
+    pub x: u16,
+    pub y: u16,
+}
+
+impl QuantizedPosition {
+    pub fn new(position: Vec2) -> QuantizedPosition {
+        QuantizedPosition {
+            x: quantize(position.x, WINDOW_SIZE),
+            y: quantize(position.y, WINDOW_SIZE),
+        }
+    }
+
+    pub fn restore(self) -> Vec2 {
+        vec2(restore(self.x, WINDOW_SIZE), restore(self.y, WINDOW_SIZE))
+    }
+}
+
+/**
+ *  A run of snake segments as the first one followed by how far each of the
+ *  others is from the one before it, in position and angle. Neighbouring
+ *  segments are close, so the steps fit in 16 bits. They wrap around just
+ *  like the arena does, so a snake crossing the edge is no problem.
+ */
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CompactBody {
+    first: Option<(QuantizedPosition, u16)>,
+    steps: Vec<[i16; 3]>,
+}
+
+impl CompactBody {
+    pub fn new(segments: &[(Vec2, f32)]) -> CompactBody {
+        let mut body = CompactBody::default();
+        let mut previous: Option<(QuantizedPosition, u16)> = None;
+        for (position, angle) in segments {
+            let current = (QuantizedPosition::new(*position), quantize(*angle, TAU));
+            match previous {
+                None => body.first = Some(current),
+                Some((position, angle)) => body.steps.push([
+                    current.0.x.wrapping_sub(position.x) as i16,
+                    current.0.y.wrapping_sub(position.y) as i16,
+                    current.1.wrapping_sub(angle) as i16,
+                ]),
+            }
+            previous = Some(current);
+        }
+        body
+    }
+
+    // Angles come back between 0 and a full turn, whatever they were before
+    pub fn restore(&self) -> Vec<(Vec2, f32)> {
+        let (mut position, mut angle) = match self.first {
+            Some(first) => first,
+            None => return vec![],
+        };
+        let mut segments = vec![(position.restore(), restore(angle, TAU))];
+        for [x, y, turn] in &self.steps {
+            position.x = position.x.wrapping_add(*x as u16);
+            position.y = position.y.wrapping_add(*y as u16);
+            angle = angle.wrapping_add(*turn as u16);
+            segments.push((position.restore(), restore(angle, TAU)));
+        }
+        segments
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompactFood {
+    index: u32,
+    position: QuantizedPosition,
+    velocity: Vec2,
+    food_type: FoodType,
+}
+
+impl CompactFood {
+    fn new((index, food): &(u32, Food)) -> CompactFood {
+        CompactFood {
+            index: *index,
+            position: QuantizedPosition::new(food.position),
+            velocity: food.velocity,
+            food_type: food.food_type,
+        }
+    }
+
+    fn restore(&self) -> (u32, Food) {
+        let food = Food {
+            position: self.position.restore(),
+            velocity: self.velocity,
+            food_type: self.food_type,
+        };
+        (self.index, food)
+    }
+}
+
+/**
+ *  A `GameStateDelta` with the snake bodies and food in compact form, which
+ *  is most of a snapshot. The rest of the delta is sent as it is, with those
+ *  parts left empty.
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompactDelta {
+    rest: GameStateDelta,
+    // The front and back of the snake of every player in the delta, in order
+    snakes: Vec<(CompactBody, CompactBody)>,
+    food: Vec<CompactFood>,
+}
+
+impl CompactDelta {
+    pub fn new(delta: &GameStateDelta) -> CompactDelta {
+        let mut rest = delta.clone();
+        let snakes = rest.players.iter_mut()
+            .map(|player| {
+                let front = mem::take(&mut player.snake.front);
+                let back = mem::take(&mut player.snake.back);
+                (CompactBody::new(&front), CompactBody::new(&back))
+            })
+            .collect();
+        let food = mem::take(&mut rest.food).iter().map(CompactFood::new).collect();
+        CompactDelta { rest, snakes, food }
+    }
+
+    pub fn restore(&self) -> GameStateDelta {
+        let mut delta = self.rest.clone();
+        for (player, (front, back)) in delta.players.iter_mut().zip(&self.snakes) {
+            player.snake.front = front.restore();
+            player.snake.back = back.restore();
+        }
+        delta.food = self.food.iter().map(CompactFood::restore).collect();
+        delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Half a step, the most quantizing can be off by
+    const POSITION_TOLERANCE: f32 = WINDOW_SIZE / STEPS / 2. + 1e-3;
+    const ANGLE_TOLERANCE: f32 = TAU / STEPS / 2. + 1e-4;
+
+    #[test]
+    fn bodies_survive_within_a_step() {
+        // Wanders across the right edge of the arena while turning all the
+        // way around
+        let segments: Vec<_> = (0..300)
+            .map(|i| {
+                let position = vec2((780. + i as f32 * 0.37) % WINDOW_SIZE, 400. - i as f32 * 0.11);
+                (position, i as f32 * 0.05)
+            })
+            .collect();
+
+        let restored = CompactBody::new(&segments).restore();
+        assert_eq!(restored.len(), segments.len());
+        for ((position, angle), (original, original_angle)) in restored.iter().zip(&segments) {
+            assert!((position.x - original.x).abs() <= POSITION_TOLERANCE);
+            assert!((position.y - original.y).abs() <= POSITION_TOLERANCE);
+            let turn = (angle - original_angle).rem_euclid(TAU);
+            assert!(turn.min(TAU - turn) <= ANGLE_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn empty_bodies_stay_empty() {
+        assert!(CompactBody::new(&[]).restore().is_empty());
+    }
+
+    #[test]
+    fn compact_bodies_are_smaller() {
+        let segments: Vec<_> = (0..100).map(|i| (vec2(i as f32 * 3., 200.), 0.1)).collect();
+        let plain = bincode::serialize(&segments).unwrap().len();
+        let compact = bincode::serialize(&CompactBody::new(&segments)).unwrap().len();
+        assert!(compact * 2 < plain + 20, "{} bytes compact, {} plain", compact, plain);
+    }
+}
@@ -1,10 +1,24 @@
 use std::f32::consts::PI;
 
-pub const DELTA_TIME: f32 = 0.01;
-pub const SERVER_SLEEP_DURATION: u64 = 10;
+// Defaults for the rules a server can change, see GameRules
+pub const TICK_RATE: u32 = 100;
+pub const MAX_AMOUNT_OF_FOOD: usize = 1000;
+// Food is spawned until there is at least this much of it
+pub const MIN_AMOUNT_OF_FOOD: usize = 10;
+// One in this many segments of a cut snake is turned into food
+pub const FOOD_CUT_STRIDE: usize = 4;
+
+// Number of past snapshots kept around to compute deltas against
+pub const SNAPSHOT_HISTORY_LENGTH: usize = 100;
+// Seconds between pings, both sides ping each other
+pub const PING_INTERVAL: f32 = 1.0;
+// How far behind the newest snapshot clients draw the game, in seconds
+pub const RENDER_DELAY: f32 = 0.05;
 
 pub const WINDOW_SIZE: f32 = 800.;
 
 pub const NAME_POS: (f32, f32) = (50., 150.);
 
 pub const GAME_DURATION: f32 = 60.0;
+// Seconds between someone starting a game and it starting
+pub const START_COUNTDOWN: f32 = 3.0;
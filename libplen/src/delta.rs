@@ -0,0 +1,257 @@
+use serde_derive::{Serialize, Deserialize};
+
+use crate::food::Food;
+use crate::gamestate::{GameRules, GameStage, GameState};
+use crate::math::Vec2;
+use crate::player::Player;
+use crate::snake::{Snake, SnakeSegment};
+
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SnakeDelta {
+    // Segments pushed onto the front of the baseline body
+    pub front: Vec<(Vec2, f32)>,
+    // Segments appended after the shifted baseline body
+    pub back: Vec<(Vec2, f32)>,
+    pub len: u32,
+    // Indices whose cuttable flag differs from the baseline
+    pub toggled_cuttable: Vec<u32>,
+    pub armor_decay: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PlayerDelta {
+    pub id: u64,
+    pub name: Option<String>,
+    pub color: usize,
+
+    pub input_x: f32,
+    pub input_y: f32,
+    pub input_start_game: bool,
+    pub input_change_color: bool,
+
+    pub snake: SnakeDelta,
+    pub player_speed: f32,
+
+    pub eat_grace_timer: i32,
+
+    pub frozen: bool,
+}
+
+/**
+ *  The difference between a baseline `GameState` that the client has
+ *  acknowledged and the current one. A delta from `GameState::new()` is
+ *  a full snapshot.
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GameStateDelta {
+    pub stage: GameStage,
+    pub game_timer: f32,
+    pub player_leaderboard: Vec<u64>,
+    pub removed_players: Vec<u64>,
+    pub players: Vec<PlayerDelta>,
+    pub food_len: u32,
+    pub food: Vec<(u32, Food)>,
+    // Only sent when they differ from the baseline
+    pub rules: Option<GameRules>,
+}
+
+
+fn pose(segment: &SnakeSegment) -> (Vec2, f32) {
+    (segment.position, segment.angle)
+}
+
+/**
+ *  Every tick each segment moves into the place of the one in front of it,
+ *  so the current body is usually the baseline body shifted back by a few
+ *  segments. Returns the smallest such shift, or the length of the current
+ *  body if it has nothing in common with the baseline.
+ */
+fn find_shift(baseline: &[SnakeSegment], current: &[SnakeSegment]) -> usize {
+    (0..current.len()).find(|&shift| {
+        current[shift..].iter()
+            .zip(baseline)
+            .all(|(a, b)| pose(a) == pose(b))
+    }).unwrap_or(current.len())
+}
+
+impl SnakeDelta {
+    pub fn between(baseline: &Snake, current: &Snake) -> SnakeDelta {
+        let shift = find_shift(&baseline.segments, &current.segments);
+        let kept = (current.len() - shift).min(baseline.len());
+
+        let toggled_cuttable = current.segments.iter()
+            .enumerate()
+            .filter(|(i, segment)| {
+                let old = baseline.segments.get(*i).map(|s| s.cuttable).unwrap_or(true);
+                segment.cuttable != old
+            })
+            .map(|(i, _)| i as u32)
+            .collect();
+
+        SnakeDelta {
+            front: current.segments[..shift].iter().map(pose).collect(),
+            back: current.segments[shift + kept..].iter().map(pose).collect(),
+            len: current.len() as u32,
+            toggled_cuttable,
+            armor_decay: current.armor_decay,
+        }
+    }
+
+    pub fn is_empty(&self, baseline: &Snake) -> bool {
+        self.front.is_empty()
+            && self.back.is_empty()
+            && self.toggled_cuttable.is_empty()
+            && self.len as usize == baseline.len()
+            && self.armor_decay == baseline.armor_decay
+    }
+
+    pub fn apply_to(&self, snake: &mut Snake) {
+        let mut segments: Vec<SnakeSegment> = self.front.iter()
+            .cloned()
+            .chain(snake.segments.iter().map(pose))
+            .chain(self.back.iter().cloned())
+            .take(self.len as usize)
+            .enumerate()
+            .map(|(i, (position, angle))| SnakeSegment {
+                position,
+                angle,
+                cuttable: snake.segments.get(i).map(|s| s.cuttable).unwrap_or(true),
+            })
+            .collect();
+
+        for &i in &self.toggled_cuttable {
+            if let Some(segment) = segments.get_mut(i as usize) {
+                segment.cuttable = !segment.cuttable;
+            }
+        }
+
+        snake.segments = segments;
+        snake.armor_decay = self.armor_decay;
+    }
+}
+
+impl PlayerDelta {
+    pub fn between(baseline: Option<&Player>, current: &Player) -> Option<PlayerDelta> {
+        let empty_snake = Snake { segments: vec![], armor_decay: 0 };
+        let snake = SnakeDelta::between(
+            baseline.map(|p| &p.snake).unwrap_or(&empty_snake),
+            &current.snake
+        );
+
+        if let Some(baseline) = baseline {
+            let unchanged = snake.is_empty(&baseline.snake)
+                && baseline.name == current.name
+                && baseline.color == current.color
+                && baseline.input_x == current.input_x
+                && baseline.input_y == current.input_y
+                && baseline.input_start_game == current.input_start_game
+                && baseline.input_change_color == current.input_change_color
+                && baseline.player_speed == current.player_speed
+                && baseline.eat_grace_timer == current.eat_grace_timer
+                && baseline.frozen == current.frozen;
+            if unchanged {
+                return None;
+            }
+        }
+
+        let name_changed = baseline.map(|p| p.name != current.name).unwrap_or(true);
+
+        Some(PlayerDelta {
+            id: current.id,
+            name: if name_changed { Some(current.name.clone()) } else { None },
+            color: current.color,
+            input_x: current.input_x,
+            input_y: current.input_y,
+            input_start_game: current.input_start_game,
+            input_change_color: current.input_change_color,
+            snake,
+            player_speed: current.player_speed,
+            eat_grace_timer: current.eat_grace_timer,
+            frozen: current.frozen,
+        })
+    }
+
+    pub fn apply_to(&self, player: &mut Player) {
+        if let Some(name) = &self.name {
+            player.name = name.clone();
+        }
+        player.color = self.color;
+        player.input_x = self.input_x;
+        player.input_y = self.input_y;
+        player.input_start_game = self.input_start_game;
+        player.input_change_color = self.input_change_color;
+        self.snake.apply_to(&mut player.snake);
+        player.player_speed = self.player_speed;
+        player.eat_grace_timer = self.eat_grace_timer;
+        player.frozen = self.frozen;
+    }
+}
+
+impl GameStateDelta {
+    pub fn between(baseline: &GameState, current: &GameState) -> GameStateDelta {
+        let removed_players = baseline.players.iter()
+            .filter(|p| current.get_player_by_id(p.id).is_none())
+            .map(|p| p.id)
+            .collect();
+
+        let players = current.players.iter()
+            .filter_map(|p| PlayerDelta::between(baseline.get_player_by_id(p.id), p))
+            .collect();
+
+        let food = current.food.iter()
+            .enumerate()
+            .filter(|(i, f)| match baseline.food.get(*i) {
+                Some(old) => old != *f,
+                // The client fills the gaps with hidden food itself
+                None => !f.is_hidden(),
+            })
+            .map(|(i, f)| (i as u32, *f))
+            .collect();
+
+        GameStateDelta {
+            stage: current.stage.clone(),
+            game_timer: current.game_timer,
+            player_leaderboard: current.player_leaderboard.clone(),
+            removed_players,
+            players,
+            food_len: current.food.len() as u32,
+            food,
+            rules: if baseline.rules != current.rules { Some(current.rules.clone()) } else { None },
+        }
+    }
+
+    pub fn apply_to(&self, state: &mut GameState) {
+        state.stage = self.stage.clone();
+        state.game_timer = self.game_timer;
+        state.player_leaderboard = self.player_leaderboard.clone();
+        if let Some(rules) = &self.rules {
+            state.rules = rules.clone();
+        }
+
+        state.players.retain(|p| !self.removed_players.contains(&p.id));
+        for delta in &self.players {
+            match state.players.iter_mut().find(|p| p.id == delta.id) {
+                Some(player) => delta.apply_to(player),
+                None => {
+                    let mut player = Player::new(delta.id, String::new());
+                    player.snake.segments.clear();
+                    delta.apply_to(&mut player);
+                    state.players.push(player);
+                }
+            }
+        }
+
+        // Entries past the end of the baseline are sent in order, and those
+        // left out between them are hidden
+        state.food.truncate(self.food_len as usize);
+        for (i, f) in &self.food {
+            let i = *i as usize;
+            if state.food.len() <= i {
+                state.food.resize(i + 1, Food::HIDDEN);
+            }
+            state.food[i] = *f;
+        }
+        state.food.resize(self.food_len as usize, Food::HIDDEN);
+    }
+}
@@ -0,0 +1,91 @@
+use serde_derive::{Serialize, Deserialize};
+
+use crate::gamestate::GameStage;
+use crate::messages::{decode_message_with_limit, FrameError, MessageError};
+
+// Clients broadcast to this port to find servers on the local network
+pub const DISCOVERY_PORT: u16 = 4445;
+// Requests are padded to this size and answers have to fit in it, so that a
+// server can not be used to amplify traffic aimed at someone else
+pub const DISCOVERY_PACKET_SIZE: usize = 256;
+const DISCOVERY_MAGIC: [u8; 4] = *b"L2DS";
+
+// What a server tells clients looking for a game
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerAnnouncement {
+    pub name: String,
+    // The protocol version of the server, clients can only join their own
+    pub version: u32,
+    pub players: u32,
+    pub max_players: u32,
+    pub stage: GameStage,
+    // The game port, answers come from the discovery port
+    pub port: u16,
+    pub password: bool,
+}
+
+pub fn discovery_request() -> Vec<u8> {
+    let mut request = DISCOVERY_MAGIC.to_vec();
+    request.resize(DISCOVERY_PACKET_SIZE, 0);
+    request
+}
+
+pub fn is_discovery_request(packet: &[u8]) -> bool {
+    packet.len() >= DISCOVERY_PACKET_SIZE && packet.starts_with(&DISCOVERY_MAGIC)
+}
+
+impl ServerAnnouncement {
+    pub fn encode(&self) -> Result<Vec<u8>, MessageError> {
+        let mut packet = DISCOVERY_MAGIC.to_vec();
+        packet.extend(bincode::serialize(self)?);
+        if packet.len() > DISCOVERY_PACKET_SIZE {
+            return Err(FrameError::TooLarge {
+                size: packet.len(),
+                max: DISCOVERY_PACKET_SIZE,
+            }.into());
+        }
+        Ok(packet)
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<ServerAnnouncement, MessageError> {
+        match packet.strip_prefix(&DISCOVERY_MAGIC) {
+            Some(payload) => decode_message_with_limit(payload, DISCOVERY_PACKET_SIZE as u64),
+            None => Err(MessageError::Invalid("not an l2 server announcement".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(name: &str) -> ServerAnnouncement {
+        ServerAnnouncement {
+            name: name.into(),
+            version: 1,
+            players: 3,
+            max_players: 8,
+            stage: GameStage::Running,
+            port: 4444,
+            password: true,
+        }
+    }
+
+    #[test]
+    fn announcements_survive_encoding() {
+        let packet = announcement("kontoret").encode().unwrap();
+        let decoded = ServerAnnouncement::decode(&packet).unwrap();
+        assert_eq!(decoded.name, "kontoret");
+        assert_eq!((decoded.players, decoded.max_players, decoded.port), (3, 8, 4444));
+        assert!(matches!(decoded.stage, GameStage::Running));
+        assert!(decoded.password);
+    }
+
+    #[test]
+    fn answers_are_never_larger_than_requests() {
+        assert!(announcement(&"x".repeat(DISCOVERY_PACKET_SIZE)).encode().is_err());
+        assert!(is_discovery_request(&discovery_request()));
+        // Short requests would let a spoofed sender get more back than it sent
+        assert!(!is_discovery_request(&discovery_request()[..DISCOVERY_PACKET_SIZE - 1]));
+    }
+}
@@ -14,14 +14,17 @@ const ARMOR_ENERGY: usize = 10;
 const ARMOR_PROBABILITY: f32 = 0.1;
 
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum FoodType {
     Normal(u32),
     Armor(usize),
+    // Food that is out of view of the player a state is sent to. It keeps the
+    // place of the real food so that the other indices stay the same
+    Hidden,
 }
 
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub struct Food {
     pub position: Vec2,
     pub velocity: Vec2,
@@ -30,6 +33,12 @@ pub struct Food {
 
 
 impl Food {
+    pub const HIDDEN: Food = Food {
+        position: Vec2 { x: 0., y: 0. },
+        velocity: Vec2 { x: 0., y: 0. },
+        food_type: FoodType::Hidden,
+    };
+
     pub fn new(position: Vec2) -> Food {
         let mut rng = rand::thread_rng();
         let x: f32 = rng.gen_range(-1.0, 1.0) * FOOD_SPEED;
@@ -63,6 +72,10 @@ impl Food {
         self.position = vec_add_wrap_around(self.position, self.velocity * delta_time, constants::WINDOW_SIZE);
     }
 
+    pub fn is_hidden(&self) -> bool {
+        self.food_type == FoodType::Hidden
+    }
+
     pub fn collides_with(&self, position: Vec2) -> bool {
         (self.position - position).norm() < FOOD_SIZE
     }
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::mpsc::Receiver;
 
 use serde_derive::{Serialize, Deserialize};
@@ -10,15 +11,102 @@ use crate::math::{Vec2, vec2};
 use crate::food::Food;
 
 
-const FOOD_CUT_STRIDE: usize = 4;
-const MAX_AMOUNT_OF_FOOD: usize = 1000;
-
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum GameStage {
     Lobby,
     Running,
     Ended,
+    // Counting down to a game. Last so that it does not change the encoding
+    // of the others in discovery answers
+    Starting,
+}
+
+
+// The parts of the game that a server can configure
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GameRules {
+    // Simulation steps per second
+    pub tick_rate: u32,
+    pub game_duration: f32,
+    pub max_food: usize,
+    pub min_food: usize,
+    pub food_cut_stride: usize,
+}
+
+// Parses a setting, with an error saying what was expected instead
+pub fn parse<T: FromStr>(value: &str, expected: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("expected {}, got `{}`", expected, value))
+}
+
+impl GameRules {
+    pub const SETTINGS: [&'static str; 5] =
+        ["tick_rate", "game_duration", "max_food", "min_food", "food_cut_stride"];
+
+    pub fn delta_time(&self) -> f32 {
+        1. / self.tick_rate as f32
+    }
+
+    // Sets one of the rules from text, as found in config files
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "tick_rate" => self.tick_rate = parse(value, "a number of ticks per second")?,
+            "game_duration" => self.game_duration = parse(value, "a number of seconds")?,
+            "max_food" => self.max_food = parse(value, "an amount of food")?,
+            "min_food" => self.min_food = parse(value, "an amount of food")?,
+            "food_cut_stride" => self.food_cut_stride = parse(value, "a number of segments")?,
+            _ => {
+                return Err(format!(
+                    "unknown rule `{}`, the rules are {}",
+                    key,
+                    Self::SETTINGS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Catches rules that parse fine but that the game can not run with
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=1000).contains(&self.tick_rate) {
+            return Err(format!("tick_rate must be between 1 and 1000, not {}", self.tick_rate));
+        }
+        if !(self.game_duration > 0. && self.game_duration.is_finite()) {
+            return Err(format!(
+                "game_duration must be a positive number of seconds, not {}",
+                self.game_duration
+            ));
+        }
+        if self.max_food > 10 * constants::MAX_AMOUNT_OF_FOOD {
+            return Err(format!(
+                "max_food can be at most {}, not {}",
+                10 * constants::MAX_AMOUNT_OF_FOOD,
+                self.max_food
+            ));
+        }
+        if self.food_cut_stride == 0 {
+            return Err("food_cut_stride must be at least 1".into());
+        }
+        if self.min_food > self.max_food {
+            return Err(format!(
+                "min_food ({}) can not be larger than max_food ({})",
+                self.min_food, self.max_food
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for GameRules {
+    fn default() -> GameRules {
+        GameRules {
+            tick_rate: constants::TICK_RATE,
+            game_duration: constants::GAME_DURATION,
+            max_food: constants::MAX_AMOUNT_OF_FOOD,
+            min_food: constants::MIN_AMOUNT_OF_FOOD,
+            food_cut_stride: constants::FOOD_CUT_STRIDE,
+        }
+    }
 }
 
 
@@ -27,18 +115,25 @@ pub struct GameState {
     pub players: Vec<Player>,
     pub food: Vec<Food>,
     pub stage: GameStage,
+    // Seconds left of the game, or of the countdown before it
     pub game_timer: f32,
     pub player_leaderboard: Vec<u64>,
+    pub rules: GameRules,
 }
 
 impl GameState {
     pub fn new() -> GameState {
+        GameState::with_rules(GameRules::default())
+    }
+
+    pub fn with_rules(rules: GameRules) -> GameState {
         GameState {
             players: Vec::new(),
             food: Vec::new(),
             stage: GameStage::Lobby,
-            game_timer: constants::GAME_DURATION,
+            game_timer: rules.game_duration,
             player_leaderboard: Vec::new(),
+            rules,
         }
     }
     
@@ -46,7 +141,7 @@ impl GameState {
     fn reset(&mut self) {
         self.food = Vec::new();
         self.stage = GameStage::Lobby;
-        self.game_timer = constants::GAME_DURATION;
+        self.game_timer = self.rules.game_duration;
         self.player_leaderboard = Vec::new();
         for player in &mut self.players {
             player.reset();
@@ -62,14 +157,32 @@ impl GameState {
      *  )
      */
     pub fn update(&mut self, sound_effects: &mut Vec<SoundEffect>, delta: f32) {
+        self.update_with_views(sound_effects, delta, &HashMap::new())
+    }
+
+    /**
+     *  Like `update`, with the game as some of the players saw it when they
+     *  steered, by id, and how many ticks before this state that was. The
+     *  others are drawn late on a slow connection, so cuts by those players
+     *  are judged against the snakes in their view rather than where the
+     *  snakes are by the time their input arrives.
+     */
+    pub fn update_with_views(
+        &mut self,
+        sound_effects: &mut Vec<SoundEffect>,
+        delta: f32,
+        views: &HashMap<u64, (&GameState, usize)>,
+    ) {
         match self.stage {
             GameStage::Running => {
                 for player in &mut self.players {
-                    player.update(delta);
+                    if !player.frozen {
+                        player.update(delta);
+                    }
                 }
                 self.update_food(delta, sound_effects);
                 self.handle_player_food(sound_effects);
-                self.handle_player_collisions(sound_effects);
+                self.handle_player_collisions(sound_effects, views);
                 self.game_timer -= delta;
                 if self.game_timer <= 0.0 {
                     self.stage = GameStage::Ended;
@@ -80,11 +193,19 @@ impl GameState {
             GameStage::Lobby => {
                 for player in &self.players {
                     if player.input_start_game {
-                        self.stage = GameStage::Running;
-                        sound_effects.push(SoundEffect::Start);
+                        self.stage = GameStage::Starting;
+                        self.game_timer = constants::START_COUNTDOWN;
                     }
                 }
             },
+            GameStage::Starting => {
+                self.game_timer -= delta;
+                if self.game_timer <= 0.0 {
+                    self.stage = GameStage::Running;
+                    self.game_timer = self.rules.game_duration;
+                    sound_effects.push(SoundEffect::Start);
+                }
+            },
             GameStage::Ended => {
                 let mut should_reset = false;
                 for player in &self.players {
@@ -106,17 +227,37 @@ impl GameState {
         self.player_leaderboard = player_lengths.iter().map(|(id, _)| *id).collect();
     }
 
-    fn handle_player_collisions(&mut self, sound_effects: &mut Vec<SoundEffect>) {
+    fn handle_player_collisions(
+        &mut self,
+        sound_effects: &mut Vec<SoundEffect>,
+        views: &HashMap<u64, (&GameState, usize)>,
+    ) {
         let mut cut_player_indices = vec![];
         for i in 0..self.players.len() {
             for j in 0..self.players.len() {
                 let player1 = &self.players[i];
                 let player2 = &self.players[j];
-                match player1.collides_with(&player2.snake) {
-                    Some(index) => {
+                // Your own snake is where you predicted it, not behind
+                let (seen, ticks_behind) = views.get(&player1.id)
+                    .filter(|_| i != j)
+                    .and_then(|(view, ticks)| {
+                        // The snakes have moved once more in this update
+                        Some((&view.get_player_by_id(player2.id)?.snake, ticks + 1))
+                    })
+                    .unwrap_or((&player2.snake, 0));
+                // Segments move one place back each tick, so the one that was
+                // hit is further from the head now. If that is past the tail,
+                // the snake has moved on and the tail is cut instead, unless
+                // it has been cut since and the segment is gone already
+                let length = player2.snake.len();
+                match player1.collides_with(seen).map(|index| index + ticks_behind) {
+                    Some(index) if index < length => {
                         cut_player_indices.push((i, j, index));
                     }
-                    None => {}
+                    Some(_) if length >= seen.len() => {
+                        cut_player_indices.push((i, j, length - 1));
+                    }
+                    _ => {}
                 }
             }
         }
@@ -126,8 +267,8 @@ impl GameState {
                 None => {},
                 Some(cut_segment_positions) => {
                     for position in cut_segment_positions
-                        .iter().step_by(FOOD_CUT_STRIDE) {
-                        if self.food.len() < MAX_AMOUNT_OF_FOOD {
+                        .iter().step_by(self.rules.food_cut_stride) {
+                        if self.food.len() < self.rules.max_food {
                             self.food.push(Food::new(*position));
                         }
                     }
@@ -171,7 +312,7 @@ impl GameState {
     }
 
     fn maybe_spawn_food(&mut self) {
-        if self.food.len() < 10 {
+        if self.food.len() < self.rules.min_food {
             let x = rand::random::<f32>() * constants::WINDOW_SIZE;
             let y = rand::random::<f32>() * constants::WINDOW_SIZE;
             self.food.push(Food::new(vec2(x, y)));
@@ -185,3 +326,91 @@ impl GameState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::snake::SnakeSegment;
+
+    // How far apart segments are at the lowest speed
+    const STEP: f32 = 2.;
+
+    // A snake heading along the angle, with its segments behind the head
+    fn player(id: u64, head: Vec2, angle: f32, length: usize) -> Player {
+        let mut player = Player::new(id, format!("orm {}", id));
+        player.snake.segments = (0..length)
+            .map(|i| SnakeSegment {
+                position: head - Vec2::from_direction(angle, i as f32 * STEP),
+                angle,
+                cuttable: true,
+            })
+            .collect();
+        player
+    }
+
+    // The victim passes in front of the cutter, whose head gets to its path
+    // just after the tail of a 20 segment snake. Returns how long the victim
+    // is afterwards, when the cutter sees the game this many ticks late
+    fn victim_length_with_latency(latency: Option<usize>, length: usize) -> usize {
+        let rules = GameRules { min_food: 0, ..GameRules::default() };
+        let mut state = GameState::with_rules(rules);
+        state.stage = GameStage::Running;
+        state.add_player(player(0, vec2(190., 400.), 0., length));
+        state.add_player(player(1, vec2(175., 360.), PI / 2., 1));
+
+        // Snapshots as the cutter receives them
+        let mut history = VecDeque::new();
+        for _ in 0..30 {
+            history.push_back(state.clone());
+            let mut views = HashMap::new();
+            if let Some(latency) = latency {
+                let seen = &history[history.len().saturating_sub(latency + 1)];
+                views.insert(1, (seen, latency));
+            }
+            state.update_with_views(&mut vec![], state.rules.delta_time(), &views);
+        }
+        state.players[0].snake.len()
+    }
+
+    #[test]
+    fn cuts_are_judged_as_the_cutter_saw_them() {
+        // The tail is long gone where the cutter crosses
+        assert_eq!(victim_length_with_latency(None, 20), 20);
+        assert_eq!(victim_length_with_latency(Some(0), 20), 20);
+        // But still there on a slow connection. It has moved on since, so
+        // the tail is what gets cut, and only once
+        assert_eq!(victim_length_with_latency(Some(10), 20), 19);
+        // A longer snake is cut at the segment the cutter saw, wherever that
+        // segment is by now
+        let length = victim_length_with_latency(None, 40);
+        assert!(length < 40);
+        assert_eq!(victim_length_with_latency(Some(10), 40), length);
+    }
+
+    #[test]
+    fn games_start_after_a_countdown() {
+        let mut state = GameState::new();
+        let mut start = player(0, vec2(400., 400.), 0., 10);
+        start.input_start_game = true;
+        state.add_player(start);
+        let mut sounds = vec![];
+
+        state.update(&mut sounds, 0.01);
+        assert!(matches!(state.stage, GameStage::Starting));
+        assert_eq!(state.game_timer, constants::START_COUNTDOWN);
+
+        let head = state.players[0].snake.segments[0].position;
+        state.update(&mut sounds, constants::START_COUNTDOWN - 0.5);
+        assert!(matches!(state.stage, GameStage::Starting));
+        assert_eq!(state.players[0].snake.segments[0].position, head);
+        assert!(sounds.is_empty());
+
+        state.update(&mut sounds, 0.5);
+        assert!(matches!(state.stage, GameStage::Running));
+        assert_eq!(state.game_timer, state.rules.game_duration);
+        assert!(matches!(sounds[..], [SoundEffect::Start]));
+    }
+}
@@ -6,3 +6,10 @@ pub mod messages;
 pub mod debug;
 pub mod snake;
 pub mod food;
+pub mod delta;
+pub mod clock;
+pub mod compact;
+pub mod discovery;
+pub mod registry;
+pub mod udp;
+pub mod websocket;
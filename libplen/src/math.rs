@@ -2,7 +2,7 @@ use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub};
 use serde_derive::{Serialize, Deserialize};
 use crate::constants;
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub struct Vec2 {
     pub x: f32,
     pub y: f32,
@@ -136,3 +136,14 @@ pub fn angle_diff(source_angle: f32, target_angle: f32) -> f32 {
     use std::f32::consts::PI;
     modulo(target_angle - source_angle + PI, 2. * PI) - PI
 }
+
+// The shortest vector from a to b when positions wrap around at div
+pub fn wrapped_difference(a: Vec2, b: Vec2, div: f32) -> Vec2 {
+    let wrap = |d: f32| modulo(d + div / 2., div) - div / 2.;
+    let difference = b - a;
+    vec2(wrap(difference.x), wrap(difference.y))
+}
+
+pub fn lerp_wrap_around(a: Vec2, b: Vec2, t: f32, div: f32) -> Vec2 {
+    vec_add_wrap_around(a, wrapped_difference(a, b, div) * t, div)
+}
@@ -1,110 +1,824 @@
+use std::fmt;
 use std::io::{self, prelude::*};
 use std::net::TcpStream;
 use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::iter::Iterator;
 
+use bincode::Options;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use serde_derive::{Serialize, Deserialize};
 
 use crate::player;
+use crate::compact::CompactDelta;
+use crate::delta::GameStateDelta;
+use crate::gamestate::GameRules;
 use crate::math::Vec2;
 
-pub struct MessageReader {
-    pub stream: TcpStream,
+// Every frame starts with the length of its payload as a big endian u32
+const FRAME_HEADER_SIZE: usize = 4;
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+// Enough for one frame of the maximum size plus the start of the next
+const DEFAULT_BUFFER_LIMIT: usize = 2 * (FRAME_HEADER_SIZE + MAX_FRAME_SIZE);
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge { size: usize, max: usize },
+    // More has been received than the reader is willing to hold on to
+    BufferFull { size: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {} bytes", size, max)
+            }
+            FrameError::BufferFull { size, max } => {
+                write!(f, "{} unread bytes exceeds the maximum of {} bytes", size, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+// Everything that can go wrong when talking to the other side
+#[derive(Debug)]
+pub enum MessageError {
+    Io(io::Error),
+    Frame(FrameError),
+    Decode(bincode::Error),
+    Json(serde_json::Error),
+    // The other side speaks another version of the protocol
+    VersionMismatch { ours: u32, theirs: u32 },
+    // The other side does not speak our protocol or would not talk to us,
+    // for the reason given
+    Rejected(String),
+    // A well formed message that no honest client would send
+    Invalid(String),
+}
+
+impl MessageError {
+    // Whether the other side simply went away, as opposed to misbehaving
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            MessageError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::Io(e) => write!(f, "{}", e),
+            MessageError::Frame(e) => write!(f, "bad frame: {}", e),
+            MessageError::Decode(e) => write!(f, "could not decode message: {}", e),
+            MessageError::Json(e) => write!(f, "could not decode JSON message: {}", e),
+            MessageError::VersionMismatch { ours, theirs } => write!(
+                f,
+                "version mismatch: the other side speaks protocol version {}, we speak {}",
+                theirs, ours
+            ),
+            MessageError::Rejected(reason) => write!(f, "{}", reason),
+            MessageError::Invalid(reason) => write!(f, "invalid message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<io::Error> for MessageError {
+    fn from(e: io::Error) -> MessageError {
+        MessageError::Io(e)
+    }
+}
+
+impl From<FrameError> for MessageError {
+    fn from(e: FrameError) -> MessageError {
+        MessageError::Frame(e)
+    }
+}
+
+impl From<bincode::Error> for MessageError {
+    fn from(e: bincode::Error) -> MessageError {
+        MessageError::Decode(e)
+    }
+}
+
+impl From<serde_json::Error> for MessageError {
+    fn from(e: serde_json::Error) -> MessageError {
+        MessageError::Json(e)
+    }
+}
+
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge { size: payload.len(), max: MAX_FRAME_SIZE });
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+// The size of the frame at the start of bytes, header included, once enough
+// has arrived to tell
+pub fn frame_length(bytes: &[u8]) -> Option<usize> {
+    let header: [u8; FRAME_HEADER_SIZE] = bytes.get(..FRAME_HEADER_SIZE)?.try_into().ok()?;
+    Some(FRAME_HEADER_SIZE + u32::from_be_bytes(header) as usize)
+}
+
+pub fn encode_message<T: serde::Serialize>(msg: &T) -> Result<Vec<u8>, MessageError> {
+    Ok(encode_frame(&bincode::serialize(msg)?)?)
+}
+
+pub fn decode_message<T: serde::de::DeserializeOwned>(frame: &[u8]) -> Result<T, MessageError> {
+    decode_message_with_limit(frame, MAX_FRAME_SIZE as u64)
+}
+
+// Fails instead of allocating if the message claims to be larger than limit
+// bytes. The options are the ones `bincode::serialize` uses, and the frame is
+// read as a stream since bincode ignores the limit when given a slice
+pub fn decode_message_with_limit<T: serde::de::DeserializeOwned>(
+    frame: &[u8],
+    limit: u64,
+) -> Result<T, MessageError> {
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit);
+    Ok(options.deserialize_from(frame)?)
+}
+
+// Decodes a message from a client with the given features, which are either
+// bincode or JSON after the handshake
+pub fn decode_message_with_features<T: serde::de::DeserializeOwned>(
+    frame: &[u8],
+    features: u32,
+    limit: u64,
+) -> Result<T, MessageError> {
+    if features & FEATURE_JSON == 0 {
+        return decode_message_with_limit(frame, limit);
+    }
+    if frame.len() as u64 > limit {
+        return Err(FrameError::TooLarge { size: frame.len(), max: limit as usize }.into());
+    }
+    Ok(serde_json::from_slice(frame)?)
+}
+
+// Writes a whole message, blocking until it has been sent
+pub fn send_message<T: serde::Serialize, W: Write>(
+    msg: &T,
+    stream: &mut W,
+) -> Result<(), MessageError> {
+    stream.write_all(&encode_message(msg)?)?;
+    // Transports that buffer writes send what is left with the next flush
+    match stream.flush() {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/**
+ *  Anything messages can be sent over. Messages carry their own framing, so
+ *  a byte stream is all that is needed, be it a plain TCP connection, a
+ *  WebSocket or a UDP connection. Reads and writes are expected not to block,
+ *  but to fail with `WouldBlock` instead. What the transports do differently
+ *  beyond that goes here, so that the server and the client do not have to
+ *  know which one they are using.
+ */
+pub trait Transport: Read + Write {
+    // Sends a whole frame that may be lost, for state that newer state
+    // replaces anyway. Returns false if the transport can not do that, the
+    // frame then has to be written like any other
+    fn send_unreliable(&mut self, _frame: &[u8]) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Transport for TcpStream {}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_unreliable(&mut self, frame: &[u8]) -> io::Result<bool> {
+        (**self).send_unreliable(frame)
+    }
+}
+
+pub struct MessageReader<S = TcpStream> {
+    pub stream: S,
     byte_queue: VecDeque<u8>,
+    buffer_limit: usize,
 }
 
-pub struct MessageIterator<'a> {
-    message_reader: &'a mut MessageReader
+pub struct MessageIterator<'a, S> {
+    message_reader: &'a mut MessageReader<S>,
+    failed: bool,
 }
 
-impl MessageReader {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: Read> MessageReader<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_buffer_limit(stream, DEFAULT_BUFFER_LIMIT)
+    }
+
+    // Frames larger than the limit are rejected, as is having more than the
+    // limit of unread bytes
+    pub fn with_buffer_limit(stream: S, buffer_limit: usize) -> Self {
         Self {
             stream,
             byte_queue: VecDeque::new(),
+            buffer_limit,
         }
     }
 
-    pub fn fetch_bytes(&mut self) -> io::Result<()> {
-        let mut buffer = [1; 64];
+    pub fn fetch_bytes(&mut self) -> Result<(), MessageError> {
+        let mut buffer = [1; 4096];
         loop {
             let amount = match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    let closed = io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed");
+                    break Err(closed.into());
+                }
                 Ok(amount) => amount,
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => 0,
-                e => e?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e.into()),
             };
-            if amount == 0 {
-                break Ok(());
-            }
             self.byte_queue.extend(buffer.iter().take(amount));
+            if self.byte_queue.len() > self.buffer_limit {
+                let size = self.byte_queue.len();
+                break Err(FrameError::BufferFull { size, max: self.buffer_limit }.into());
+            }
         }
     }
 
-    pub fn iter<'a>(&'a mut self) -> MessageIterator<'a> {
+    pub fn iter<'a>(&'a mut self) -> MessageIterator<'a, S> {
         MessageIterator {
-            message_reader: self
+            message_reader: self,
+            failed: false,
         }
     }
 }
 
-impl Iterator for MessageIterator<'_> {
-    type Item = Vec<u8>;
+impl<S> Iterator for MessageIterator<'_, S> {
+    type Item = Result<Vec<u8>, FrameError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // We need two bytes for the length
-        if self.message_reader.byte_queue.len() < 2 {
+        let max = MAX_FRAME_SIZE.min(self.message_reader.buffer_limit.saturating_sub(FRAME_HEADER_SIZE));
+        let queue = &mut self.message_reader.byte_queue;
+        if self.failed || queue.len() < FRAME_HEADER_SIZE {
             return None;
         }
 
-        let length = u16::from_be_bytes([
-            self.message_reader.byte_queue[0],
-            self.message_reader.byte_queue[1],
-        ]) as usize;
+        let mut header = [0; FRAME_HEADER_SIZE];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = queue[i];
+        }
+        let length = u32::from_be_bytes(header) as usize;
+
+        // The rest of the stream can not be trusted after a bad header, so
+        // the frame is left in the queue and every new iterator reports the
+        // error again
+        if length > max {
+            self.failed = true;
+            return Some(Err(FrameError::TooLarge { size: length, max }));
+        }
 
         // We will not read a message until a complete message has been
         // received
-        if self.message_reader.byte_queue.len() < 2 + length {
+        if queue.len() < FRAME_HEADER_SIZE + length {
             return None;
         }
 
-        self.message_reader.byte_queue.pop_front().unwrap();
-        self.message_reader.byte_queue.pop_front().unwrap();
+        queue.drain(0..FRAME_HEADER_SIZE);
+        Some(Ok(queue.drain(0..length).collect()))
+    }
+}
+
+// Bump this whenever a change to the messages below means that builds from
+// before and after can no longer understand each other
+pub const PROTOCOL_VERSION: u32 = 11;
+// Optional parts of the protocol, negotiated in the handshake. Servers send
+// snapshots as `CompactSnapshot` to clients with the first, and may wrap
+// messages in `Compressed` for clients with the second. With the third all
+// messages after the handshake are JSON, see PROTOCOL.md
+pub const FEATURE_COMPACT_STATE: u32 = 1 << 0;
+pub const FEATURE_COMPRESSION: u32 = 1 << 1;
+pub const FEATURE_JSON: u32 = 1 << 2;
+pub const SUPPORTED_FEATURES: u32 = FEATURE_COMPACT_STATE | FEATURE_COMPRESSION | FEATURE_JSON;
+// JSON is for clients written in other languages, ours use bincode
+const CLIENT_FEATURES: u32 = SUPPORTED_FEATURES & !FEATURE_JSON;
+// Smaller messages are not worth compressing
+const COMPRESSION_THRESHOLD: usize = 256;
+const HANDSHAKE_MAGIC: [u8; 4] = *b"L2GM";
+
+/**
+ *  The first frame in each direction. These are plain structs rather than
+ *  message variants so that their encoding does not change when messages are
+ *  added, and must never be changed themselves.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct ClientHello {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub features: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerHello {
+    pub magic: [u8; 4],
+    pub version: u32,
+    // The features both sides support
+    pub features: u32,
+    pub rejection: Option<String>,
+}
+
+impl ClientHello {
+    pub fn new() -> Self {
+        ClientHello {
+            magic: HANDSHAKE_MAGIC,
+            version: PROTOCOL_VERSION,
+            features: CLIENT_FEATURES,
+        }
+    }
+}
+
+impl ServerHello {
+    pub fn answer(hello: &ClientHello) -> Self {
+        let rejection = if hello.magic != HANDSHAKE_MAGIC {
+            Some("Not an l2 client".to_string())
+        } else if hello.version != PROTOCOL_VERSION {
+            Some(format!(
+                "Server speaks protocol version {}, client speaks version {}",
+                PROTOCOL_VERSION, hello.version
+            ))
+        } else {
+            None
+        };
+
+        // The other features are ways of making bincode smaller
+        let mut features = hello.features & SUPPORTED_FEATURES;
+        if features & FEATURE_JSON != 0 {
+            features = FEATURE_JSON;
+        }
+
+        ServerHello {
+            magic: HANDSHAKE_MAGIC,
+            version: PROTOCOL_VERSION,
+            features,
+            rejection,
+        }
+    }
+
+    // Whether this came from a server we can talk to
+    pub fn is_compatible(&self) -> bool {
+        self.magic == HANDSHAKE_MAGIC
+            && self.version == PROTOCOL_VERSION
+            && self.rejection.is_none()
+    }
+
+    // Whether this came from a server that speaks another protocol version
+    pub fn is_other_version(&self) -> bool {
+        self.magic == HANDSHAKE_MAGIC && self.version != PROTOCOL_VERSION
+    }
+}
+
+// Proves that a client knows the server password without sending it. The
+// server hands every connection a random challenge, and the client answers
+// with a MAC of it keyed by the password, so an overheard proof is useless on
+// any other connection
+pub type PasswordChallenge = [u8; 16];
+pub type PasswordProof = [u8; 32];
+
+pub fn password_proof(password: &str, challenge: &PasswordChallenge) -> PasswordProof {
+    hmac_sha256::HMAC::mac(challenge, password.as_bytes())
+}
+
+// Compares in constant time so that the proof can not be guessed byte by byte
+pub fn check_password_proof(
+    password: &str,
+    challenge: &PasswordChallenge,
+    proof: &PasswordProof,
+) -> bool {
+    hmac_sha256::HMAC::verify(challenge, password.as_bytes(), proof)
+}
+
+// Session tokens are 64 bit secrets, more than a JSON number read as a double
+// can hold. JSON has them as strings, bincode as they are
+mod token_encoding {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(token: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&token.to_string())
+        } else {
+            serializer.serialize_u64(*token)
+        }
+    }
 
-        Some(self.message_reader.byte_queue.drain(0..length).collect())
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+        } else {
+            u64::deserialize(deserializer)
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub enum SoundEffect { Welcome, Eat, Cut, FoodBounce, Start, End }
 
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    // Counts the ticks of the room
+    pub id: u64,
+    // Seconds on the server clock when the tick was simulated
+    pub server_time: f64,
+    // The acknowledged snapshot this is a delta from, or None if it is a
+    // delta from an empty game state
+    pub baseline: Option<u64>,
+    // Sequence number of the last input applied before this snapshot
+    pub last_processed_input: u64,
+    pub delta: GameStateDelta,
+}
+
+// A snapshot with positions quantized, see `compact`
+#[derive(Serialize, Deserialize)]
+pub struct CompactSnapshot {
+    pub id: u64,
+    pub server_time: f64,
+    pub baseline: Option<u64>,
+    pub last_processed_input: u64,
+    pub delta: CompactDelta,
+}
+
+impl CompactSnapshot {
+    pub fn restore(&self) -> Snapshot {
+        Snapshot {
+            id: self.id,
+            server_time: self.server_time,
+            baseline: self.baseline,
+            last_processed_input: self.last_processed_input,
+            delta: self.delta.restore(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomInfo {
+    pub name: String,
+    pub players: u32,
+    pub max_players: u32,
+    pub rules: GameRules,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum ServerMessage {
-    AssignId(u64),
-    GameState(crate::gamestate::GameState),
+    // The challenge is only sent by servers that have a password
+    AssignId {
+        id: u64,
+        #[serde(with = "token_encoding")]
+        token: u64,
+        challenge: Option<PasswordChallenge>,
+    },
+    Resumed { id: u64 },
+    ResumeRejected,
+    Snapshot(Snapshot),
+    CompactSnapshot(CompactSnapshot),
     PlaySound(SoundEffect),
+    // Sent instead of adding a player when the game has no room for one
+    GameFull,
+    // Why a join or spectate request was turned down, the connection is
+    // closed after this
+    JoinRejected(String),
+    RoomList(Vec<RoomInfo>),
+    // From now on all snapshots are of this room
+    JoinedRoom { name: String },
+    // Why a room could not be created or joined
+    RoomError(String),
+    // Pings are answered with a pong carrying the same number
+    Ping(u64),
+    Pong(u64),
+    // The answer to `SyncClock`, with the server clock when it was handled
+    ClockSync { sent: f64, server_time: f64 },
+    // Another message, deflated
+    Compressed(Vec<u8>),
 }
 
-#[derive(Serialize, Deserialize)]
+impl ServerMessage {
+    // Undoes the compression and compact encoding that servers use for
+    // clients that support them, so that only plain messages are left
+    pub fn unpack(self) -> Result<ServerMessage, MessageError> {
+        match self {
+            ServerMessage::Compressed(data) => {
+                let mut payload = vec![];
+                // Anything that inflates to more than a frame is a bomb
+                DeflateDecoder::new(&data[..])
+                    .take(MAX_FRAME_SIZE as u64 + 1)
+                    .read_to_end(&mut payload)?;
+                if payload.len() > MAX_FRAME_SIZE {
+                    return Err(FrameError::TooLarge { size: payload.len(), max: MAX_FRAME_SIZE }.into());
+                }
+                match decode_message(&payload)? {
+                    ServerMessage::Compressed(_) => {
+                        Err(MessageError::Invalid("compressed twice".into()))
+                    }
+                    msg => msg.unpack(),
+                }
+            }
+            ServerMessage::CompactSnapshot(snapshot) => {
+                Ok(ServerMessage::Snapshot(snapshot.restore()))
+            }
+            msg => Ok(msg),
+        }
+    }
+}
+
+// Encodes a message for a client with the given features. Large messages
+// are compressed if the client supports that and it makes them smaller
+pub fn encode_server_message(msg: &ServerMessage, features: u32) -> Result<Vec<u8>, MessageError> {
+    if features & FEATURE_JSON != 0 {
+        return Ok(encode_frame(&serde_json::to_vec(msg)?)?);
+    }
+    let payload = bincode::serialize(msg)?;
+    if features & FEATURE_COMPRESSION != 0 && payload.len() >= COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&payload)?;
+        let compressed = bincode::serialize(&ServerMessage::Compressed(encoder.finish()?))?;
+        if compressed.len() < payload.len() {
+            return Ok(encode_frame(&compressed)?);
+        }
+    }
+    Ok(encode_frame(&payload)?)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ClientInput {
+    pub sequence: u64,
     pub x_input: f32,
     pub y_input: f32,
     pub start_game: bool,
     pub change_color: bool,
+    // The snapshot that was on screen, cuts are judged against it
+    pub view: Option<u64>,
 }
 
 impl ClientInput {
     pub fn new() -> Self {
         ClientInput {
+            sequence: 0,
             x_input: 0.,
             y_input: 0.,
             start_game: false,
             change_color: false,
+            view: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
     Input(ClientInput),
-    JoinGame { name: String },
+    // The proof is needed once per connection if the server has a password
+    JoinGame { name: String, password: Option<PasswordProof> },
+    // Receive the game state without being part of the game
+    Spectate { password: Option<PasswordProof> },
+    AckSnapshot(u64),
+    // Take over the player of a dropped connection using the token it was
+    // assigned
+    Resume {
+        #[serde(with = "token_encoding")]
+        token: u64,
+    },
+    Ping(u64),
+    Pong(u64),
+    // Asks for the server clock, sent is the time on the client clock
+    SyncClock { sent: f64 },
+    // The client is going away, its player is removed right away instead of
+    // waiting for it to resume
+    Leave,
+    ListRooms,
+    // Everyone starts out in the room of the server config. Other rooms can
+    // only be created or joined before joining the game or spectating
+    CreateRoom { name: String, rules: GameRules },
+    JoinRoom { name: String },
+    // Answers the challenge of a server with a password. Rooms can not be
+    // listed, created or joined before this, or before a join or spectate
+    // request with the proof
+    Authenticate(PasswordProof),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (reader, _) = listener.accept().unwrap();
+        reader.set_nonblocking(true).unwrap();
+        (writer, reader)
+    }
+
+    fn round_trip(payload: Vec<u8>) -> Vec<u8> {
+        let (mut writer, reader) = connected_pair();
+        let frame = encode_frame(&payload).unwrap();
+        // The writer is kept open until everything has been read, since
+        // a closed connection is an error for the reader
+        let sender = thread::spawn(move || {
+            writer.write_all(&frame).unwrap();
+            writer
+        });
+
+        let mut message_reader = MessageReader::new(reader);
+        let received = loop {
+            message_reader.fetch_bytes().unwrap();
+            if let Some(message) = message_reader.iter().next() {
+                break message.unwrap();
+            }
+            thread::yield_now();
+        };
+        sender.join().unwrap();
+        received
+    }
+
+    #[test]
+    fn empty_frame_round_trips() {
+        assert_eq!(round_trip(vec![]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn frame_longer_than_u16_prefix_round_trips() {
+        let payload: Vec<u8> = (0..65535).map(|i| i as u8).collect();
+        assert_eq!(round_trip(payload.clone()), payload);
+    }
+
+    #[test]
+    fn multi_megabyte_frame_round_trips() {
+        let payload: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        assert_eq!(round_trip(payload.clone()), payload);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let payload = vec![0; MAX_FRAME_SIZE + 1];
+        assert!(matches!(encode_frame(&payload), Err(FrameError::TooLarge { .. })));
+
+        let (mut writer, reader) = connected_pair();
+        writer.write_all(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes()).unwrap();
+        let mut message_reader = MessageReader::new(reader);
+        let error = loop {
+            message_reader.fetch_bytes().unwrap();
+            if let Some(message) = message_reader.iter().next() {
+                break message.unwrap_err();
+            }
+            thread::yield_now();
+        };
+        assert!(matches!(error, FrameError::TooLarge { .. }));
+    }
+
+    #[test]
+    fn buffer_limit_is_enforced() {
+        let (mut writer, reader) = connected_pair();
+        let mut message_reader = MessageReader::with_buffer_limit(reader, 64);
+
+        // A frame that could never fit is rejected from its header alone
+        writer.write_all(&100u32.to_be_bytes()).unwrap();
+        let error = loop {
+            message_reader.fetch_bytes().unwrap();
+            if let Some(message) = message_reader.iter().next() {
+                break message.unwrap_err();
+            }
+            thread::yield_now();
+        };
+        assert!(matches!(error, FrameError::TooLarge { size: 100, max: 60 }));
+
+        // As is sending more than the limit without it being read
+        writer.write_all(&[0; 64]).unwrap();
+        let error = loop {
+            if let Err(e) = message_reader.fetch_bytes() {
+                break e;
+            }
+            thread::yield_now();
+        };
+        assert!(matches!(error, MessageError::Frame(FrameError::BufferFull { .. })));
+    }
+
+    #[test]
+    fn decoding_respects_the_size_limit() {
+        let join = ClientMessage::JoinGame { name: "a".repeat(1000), password: None };
+        let data = bincode::serialize(&join).unwrap();
+        assert!(decode_message_with_limit::<ClientMessage>(&data, 2000).is_ok());
+        assert!(matches!(
+            decode_message_with_limit::<ClientMessage>(&data, 100),
+            Err(MessageError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn password_proofs_only_work_for_their_challenge() {
+        let challenge = [7; 16];
+        let proof = password_proof("hemligt", &challenge);
+        assert!(check_password_proof("hemligt", &challenge, &proof));
+        assert!(!check_password_proof("gissning", &challenge, &proof));
+        assert!(!check_password_proof("hemligt", &[8; 16], &proof));
+    }
+
+    #[test]
+    fn compression_is_only_used_when_asked_for() {
+        let rooms = (0..20)
+            .map(|i| RoomInfo {
+                name: format!("rum {}", i),
+                players: 0,
+                max_players: 8,
+                rules: GameRules::default(),
+            })
+            .collect();
+        let message = ServerMessage::RoomList(rooms);
+
+        let plain = encode_server_message(&message, 0).unwrap();
+        let compressed = encode_server_message(&message, FEATURE_COMPRESSION).unwrap();
+        assert!(compressed.len() < plain.len());
+
+        let decoded: ServerMessage = decode_message(&compressed[FRAME_HEADER_SIZE..]).unwrap();
+        assert!(matches!(decoded, ServerMessage::Compressed(_)));
+        match decoded.unpack().unwrap() {
+            ServerMessage::RoomList(rooms) => assert_eq!(rooms[19].name, "rum 19"),
+            _ => panic!("Unpacked into something else"),
+        }
+    }
+
+    #[test]
+    fn only_other_versions_are_called_a_version_mismatch() {
+        let old = ClientHello { version: PROTOCOL_VERSION - 1, ..ClientHello::new() };
+        let answer = ServerHello::answer(&old);
+        assert!(answer.rejection.is_some() && !answer.is_other_version());
+        let server = ServerHello { version: PROTOCOL_VERSION + 1, ..answer };
+        assert!(server.is_other_version());
+
+        let error = MessageError::VersionMismatch { ours: 11, theirs: 12 };
+        assert!(error.to_string().starts_with("version mismatch"));
+        let error = MessageError::Rejected("not an l2 server".into());
+        assert_eq!(error.to_string(), "not an l2 server");
+    }
+
+    #[test]
+    fn json_is_plain_and_tagged_by_name() {
+        let hello = ClientHello { features: SUPPORTED_FEATURES, ..ClientHello::new() };
+        assert_eq!(ServerHello::answer(&hello).features, FEATURE_JSON);
+
+        let frame = encode_server_message(&ServerMessage::Pong(5), FEATURE_JSON).unwrap();
+        assert_eq!(&frame[FRAME_HEADER_SIZE..], br#"{"Pong":5}"#);
+        let rooms = ServerMessage::RoomList(vec![]);
+        let frame = encode_server_message(&rooms, FEATURE_JSON | FEATURE_COMPRESSION).unwrap();
+        assert_eq!(&frame[FRAME_HEADER_SIZE..], br#"{"RoomList":[]}"#);
+
+        let join = br#"{"JoinGame": {"name": "bot", "password": null}}"#;
+        match decode_message_with_features(join, FEATURE_JSON, 100).unwrap() {
+            ClientMessage::JoinGame { name, password: None } => assert_eq!(name, "bot"),
+            msg => panic!("Decoded into {:?}", msg),
+        }
+        let leave = br#""Leave""#;
+        assert!(matches!(
+            decode_message_with_features(leave, FEATURE_JSON, 100),
+            Ok(ClientMessage::Leave)
+        ));
+        assert!(matches!(
+            decode_message_with_features::<ClientMessage>(leave, FEATURE_JSON, 4),
+            Err(MessageError::Frame(FrameError::TooLarge { .. }))
+        ));
+    }
+
+    #[test]
+    fn tokens_keep_all_their_bits() {
+        let token = u64::MAX - 1;
+        let assign = ServerMessage::AssignId { id: 1, token, challenge: None };
+        let frame = encode_server_message(&assign, FEATURE_JSON).unwrap();
+        let json = std::str::from_utf8(&frame[FRAME_HEADER_SIZE..]).unwrap();
+        assert!(json.contains(r#""token":"18446744073709551614""#), "{}", json);
+
+        let resume = br#"{"Resume": {"token": "18446744073709551614"}}"#;
+        assert!(matches!(
+            decode_message_with_features(resume, FEATURE_JSON, 100),
+            Ok(ClientMessage::Resume { token: decoded }) if decoded == token
+        ));
+        let frame = encode_message(&ClientMessage::Resume { token }).unwrap();
+        assert!(matches!(
+            decode_message_with_features(&frame[FRAME_HEADER_SIZE..], 0, 100),
+            Ok(ClientMessage::Resume { token: decoded }) if decoded == token
+        ));
+    }
 }
@@ -28,6 +28,9 @@ pub struct Player {
     pub player_speed: f32,
 
     pub eat_grace_timer: i32,
+
+    // Set while the player's connection is gone, waiting for it to resume
+    pub frozen: bool,
 }
 
 
@@ -50,6 +53,8 @@ impl Player {
             player_speed: PLAYER_MIN_SPEED,
 
             eat_grace_timer: 0,
+
+            frozen: false,
         }
     }
 
@@ -84,7 +89,7 @@ impl Player {
     }
 
     pub fn try_eat(&mut self, food: &Food) -> bool {
-        if self.eat_grace_timer > 0 {
+        if self.eat_grace_timer > 0 || food.is_hidden() {
             return false;
         }
         match food.food_type {
@@ -110,6 +115,7 @@ impl Player {
                     None => {}
                 }
             },
+            Hidden => {}
         }
         true
     }
@@ -0,0 +1,69 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::discovery::ServerAnnouncement;
+use crate::gamestate::GameRules;
+use crate::messages::{decode_message, send_message, MessageError, MessageReader};
+
+pub const REGISTRY_PORT: u16 = 4446;
+// Game servers register this often, and the registry forgets servers it has
+// not heard from in a few of these
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(20);
+// How long a request to the registry may take, connecting included
+pub const REGISTRY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerListing {
+    // Where clients connect. The registry fills this in from the address the
+    // registration came from, since servers behind NAT do not know it
+    pub address: SocketAddr,
+    pub announcement: ServerAnnouncement,
+    pub rules: GameRules,
+}
+
+/**
+ *  Every connection to the registry carries one request and its reply, after
+ *  which the registry hangs up. Both are framed as in `messages`.
+ */
+#[derive(Serialize, Deserialize)]
+pub enum RegistryRequest {
+    Register { announcement: ServerAnnouncement, rules: GameRules },
+    List,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum RegistryReply {
+    Registered,
+    Servers(Vec<ServerListing>),
+    Rejected(String),
+}
+
+// Sends a request to the registry at the address and waits for the reply
+pub fn request(address: &str, request: &RegistryRequest) -> Result<RegistryReply, MessageError> {
+    let address = address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not resolve registry"))?;
+    let mut stream = TcpStream::connect_timeout(&address, REGISTRY_TIMEOUT)?;
+    send_message(request, &mut stream)?;
+    stream.set_nonblocking(true)?;
+
+    let mut reader = MessageReader::new(stream);
+    let start = Instant::now();
+    loop {
+        // The registry hangs up right after replying, so the reply may come
+        // along with the end of the connection
+        let fetched = reader.fetch_bytes();
+        if let Some(frame) = reader.iter().next() {
+            return decode_message(&frame?);
+        }
+        fetched?;
+        if start.elapsed() > REGISTRY_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No answer from registry").into());
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
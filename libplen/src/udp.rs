@@ -0,0 +1,488 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::messages::{decode_message_with_limit, frame_length, MessageError, Transport};
+
+// Servers listen for UDP connections on this port, next to the TCP one
+pub const UDP_PORT: u16 = 4448;
+// The largest datagram either side sends or accepts. Anything above the path
+// MTU is fragmented by IP, which works but is lost more often
+pub const MAX_DATAGRAM_SIZE: usize = 60 * 1024;
+// Room for the packet header around an unreliable frame. Larger frames are
+// sent reliably instead
+const MAX_UNRELIABLE_FRAME: usize = MAX_DATAGRAM_SIZE - 64;
+// Reliable data is split into chunks that fit in one unfragmented datagram
+const CHUNK_SIZE: usize = 1024;
+// Most chunks that can be waiting for an ack at once
+const WINDOW: usize = 256;
+const RESEND_INTERVAL: Duration = Duration::from_millis(250);
+// A connection where nothing sent gets acknowledged for this long is dead
+const RELIABLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Proves that a client receives what is sent to the address it sends from
+pub type Cookie = [u8; 16];
+
+/**
+ *  Everything sent over a UDP connection is one of these, bincode encoded
+ *  with one packet per datagram. Reliable data is a byte stream of framed
+ *  messages cut into numbered chunks, which are resent until the other side
+ *  acks them. Unreliable packets carry one whole frame each, and only the
+ *  newest one to arrive counts.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Packet {
+    // Picked at random by the client, so that others can not forge packets
+    // of the connection by spoofing its address
+    pub connection: u64,
+    // The next reliable chunk the sender is waiting for, everything before
+    // it has arrived
+    pub ack: u64,
+    pub body: PacketBody,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum PacketBody {
+    Reliable { sequence: u64, data: Vec<u8> },
+    Unreliable { sequence: u64, frame: Vec<u8> },
+    Ack,
+    // The sender hung up
+    Close,
+    // The server answers the first packet of a connection with a cookie, and
+    // only opens the connection once the client sends it back. Until then
+    // nothing larger than what arrived goes to the address, which may be
+    // forged
+    Cookie(Cookie),
+}
+
+impl Packet {
+    pub fn decode(datagram: &[u8]) -> Result<Packet, MessageError> {
+        decode_message_with_limit(datagram, MAX_DATAGRAM_SIZE as u64)
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Only the first chunk of a connection may open it
+    pub fn opens_connection(&self) -> bool {
+        matches!(self.body, PacketBody::Reliable { sequence: 0, .. })
+    }
+}
+
+// The cookie a server hands the connection from this address. The epoch
+// should change every so often, so that old cookies stop working
+pub fn connection_cookie(
+    secret: &[u8; 32],
+    source: SocketAddr,
+    connection: u64,
+    epoch: u64,
+) -> Cookie {
+    let mut data = source.to_string().into_bytes();
+    data.extend_from_slice(&connection.to_le_bytes());
+    data.extend_from_slice(&epoch.to_le_bytes());
+    let mac = hmac_sha256::HMAC::mac(&data, secret);
+    let mut cookie = [0; 16];
+    cookie.copy_from_slice(&mac[..16]);
+    cookie
+}
+
+pub trait DatagramSocket {
+    fn send_datagram(&self, data: &[u8]) -> io::Result<()>;
+
+    // A server has one socket for all clients, and hands each connection its
+    // packets instead of letting it receive them
+    fn receive_datagram(&self, _buffer: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+// Clients connect their socket to the server
+impl DatagramSocket for UdpSocket {
+    fn send_datagram(&self, data: &[u8]) -> io::Result<()> {
+        self.send(data).map(|_| ())
+    }
+
+    fn receive_datagram(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.recv(buffer)
+    }
+}
+
+/**
+ *  One side of a UDP connection, which reads and writes like a byte stream
+ *  so that a `MessageReader` can be put on top of it. Frames are only handed
+ *  to the reader whole, which keeps reliable and unreliable ones apart.
+ *  Resends happen when the connection is read from or flushed, so one of
+ *  those should be done regularly.
+ */
+pub struct UdpConnection<S: DatagramSocket> {
+    socket: S,
+    id: u64,
+    next_sequence: u64,
+    // Chunks sent but not acknowledged, oldest first
+    in_flight: VecDeque<(u64, Vec<u8>)>,
+    resend_timer: Instant,
+    // When the other side last acknowledged anything
+    last_progress: Instant,
+    next_unreliable: u64,
+    ack_due: bool,
+    next_expected: u64,
+    // Reliable data that does not yet make up a whole frame
+    partial: Vec<u8>,
+    // Whole frames waiting to be read
+    ready: VecDeque<u8>,
+    newest_unreliable: Option<u64>,
+    closed: bool,
+    buffer_limit: usize,
+    receive_buffer: Vec<u8>,
+}
+
+impl<S: DatagramSocket> UdpConnection<S> {
+    // The buffer limit applies to received data that has not been read
+    pub fn new(socket: S, id: u64, buffer_limit: usize) -> Self {
+        UdpConnection {
+            socket,
+            id,
+            next_sequence: 0,
+            in_flight: VecDeque::new(),
+            resend_timer: Instant::now(),
+            last_progress: Instant::now(),
+            next_unreliable: 0,
+            ack_due: false,
+            next_expected: 0,
+            partial: vec![],
+            ready: VecDeque::new(),
+            newest_unreliable: None,
+            closed: false,
+            buffer_limit,
+            receive_buffer: vec![],
+        }
+    }
+
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    // Packets of other connections are ignored, they may well be forged
+    pub fn receive_packet(&mut self, packet: Packet) -> io::Result<()> {
+        if packet.connection != self.id {
+            return Ok(());
+        }
+        let in_flight = self.in_flight.len();
+        while self.in_flight.front().map(|(sequence, _)| *sequence < packet.ack).unwrap_or(false) {
+            self.in_flight.pop_front();
+        }
+        if self.in_flight.len() < in_flight {
+            self.last_progress = Instant::now();
+            self.resend_timer = Instant::now();
+        }
+
+        match packet.body {
+            PacketBody::Reliable { sequence, data } => {
+                // Duplicates are acked again, in case the first ack was lost
+                self.ack_due = true;
+                // Chunks after a lost one are dropped, they are resent along
+                // with it
+                if sequence != self.next_expected {
+                    return Ok(());
+                }
+                self.next_expected += 1;
+                self.partial.extend(data);
+                while let Some(length) = frame_length(&self.partial) {
+                    if length > self.partial.len() {
+                        break;
+                    }
+                    self.ready.extend(self.partial.drain(..length));
+                }
+            }
+            PacketBody::Unreliable { sequence, frame } => {
+                let newer = self.newest_unreliable.map(|newest| sequence > newest).unwrap_or(true);
+                if newer && frame_length(&frame) == Some(frame.len()) {
+                    self.newest_unreliable = Some(sequence);
+                    self.ready.extend(frame);
+                }
+            }
+            PacketBody::Ack => {}
+            PacketBody::Close => self.closed = true,
+            // Only clients get cookies. Everything sent so far was dropped by
+            // the server, so it goes out again right away
+            PacketBody::Cookie(cookie) => {
+                self.send_packet(PacketBody::Cookie(cookie))?;
+                self.resend_all()?;
+            }
+        }
+
+        let unread = self.partial.len() + self.ready.len();
+        if unread > self.buffer_limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} unread bytes exceeds the maximum of {} bytes", unread, self.buffer_limit),
+            ));
+        }
+        Ok(())
+    }
+
+    fn send_packet(&mut self, body: PacketBody) -> io::Result<()> {
+        let packet = Packet { connection: self.id, ack: self.next_expected, body };
+        let datagram = packet.encode()?;
+        self.ack_due = false;
+        match self.socket.send_datagram(&datagram) {
+            // The packet is as good as lost then, which is handled anyway
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    // Sends everything that has not been acknowledged again, if the ack is
+    // overdue
+    fn resend(&mut self) -> io::Result<()> {
+        if self.in_flight.is_empty() || self.resend_timer.elapsed() < RESEND_INTERVAL {
+            return Ok(());
+        }
+        if self.last_progress.elapsed() > RELIABLE_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Nothing sent was acknowledged"));
+        }
+        self.resend_all()
+    }
+
+    fn resend_all(&mut self) -> io::Result<()> {
+        self.resend_timer = Instant::now();
+        let chunks: Vec<_> = self.in_flight.iter().cloned().collect();
+        for (sequence, data) in chunks {
+            self.send_packet(PacketBody::Reliable { sequence, data })?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: DatagramSocket> Read for UdpConnection<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.receive_buffer.resize(MAX_DATAGRAM_SIZE, 0);
+        loop {
+            match self.socket.receive_datagram(&mut self.receive_buffer) {
+                Ok(size) => {
+                    // Anyone can send us datagrams, junk is ignored
+                    if let Ok(packet) = Packet::decode(&self.receive_buffer[..size]) {
+                        self.receive_packet(packet)?;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.resend()?;
+        if self.ack_due {
+            self.send_packet(PacketBody::Ack)?;
+        }
+
+        if self.ready.is_empty() {
+            return match self.closed {
+                true => Ok(0),
+                false => Err(io::ErrorKind::WouldBlock.into()),
+            };
+        }
+        let amount = buffer.len().min(self.ready.len());
+        for (byte, value) in buffer.iter_mut().zip(self.ready.drain(..amount)) {
+            *byte = value;
+        }
+        Ok(amount)
+    }
+}
+
+impl<S: DatagramSocket> Write for UdpConnection<S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let mut written = 0;
+        while written < data.len() && self.in_flight.len() < WINDOW {
+            let chunk = data[written..].iter().take(CHUNK_SIZE).copied().collect::<Vec<_>>();
+            written += chunk.len();
+            if self.in_flight.is_empty() {
+                self.last_progress = Instant::now();
+                self.resend_timer = Instant::now();
+            }
+            let sequence = self.next_sequence;
+            self.next_sequence += 1;
+            self.in_flight.push_back((sequence, chunk.clone()));
+            self.send_packet(PacketBody::Reliable { sequence, data: chunk })?;
+        }
+        if written == 0 && !data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.resend()?;
+        if self.ack_due {
+            self.send_packet(PacketBody::Ack)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: DatagramSocket> Transport for UdpConnection<S> {
+    fn send_unreliable(&mut self, frame: &[u8]) -> io::Result<bool> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if frame.len() > MAX_UNRELIABLE_FRAME {
+            return Ok(false);
+        }
+        let sequence = self.next_unreliable;
+        self.next_unreliable += 1;
+        self.send_packet(PacketBody::Unreliable { sequence, frame: frame.to_vec() })?;
+        Ok(true)
+    }
+}
+
+// Lets the other side know right away, rather than after a timeout
+impl<S: DatagramSocket> Drop for UdpConnection<S> {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.send_packet(PacketBody::Close).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::messages::{decode_message, encode_message, MessageReader};
+
+    type Wire = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+    // Delivers datagrams in memory, losing the ones it is told to
+    struct TestSocket {
+        outgoing: Wire,
+        incoming: Wire,
+        sent: RefCell<usize>,
+        lose: Vec<usize>,
+    }
+
+    impl DatagramSocket for TestSocket {
+        fn send_datagram(&self, data: &[u8]) -> io::Result<()> {
+            let number = *self.sent.borrow();
+            *self.sent.borrow_mut() += 1;
+            if !self.lose.contains(&number) {
+                self.outgoing.borrow_mut().push_back(data.to_vec());
+            }
+            Ok(())
+        }
+
+        fn receive_datagram(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            let datagram = self.incoming.borrow_mut().pop_front()
+                .ok_or(io::ErrorKind::WouldBlock)?;
+            buffer[..datagram.len()].copy_from_slice(&datagram);
+            Ok(datagram.len())
+        }
+    }
+
+    fn connection_pair(
+        lose: Vec<usize>,
+    ) -> (UdpConnection<TestSocket>, MessageReader<UdpConnection<TestSocket>>, Wire) {
+        let (there, back) = (Wire::default(), Wire::default());
+        let sender = TestSocket {
+            outgoing: there.clone(),
+            incoming: back.clone(),
+            sent: RefCell::new(0),
+            lose,
+        };
+        let receiver = TestSocket {
+            outgoing: back,
+            incoming: there.clone(),
+            sent: RefCell::new(0),
+            lose: vec![],
+        };
+        let receiver = MessageReader::new(UdpConnection::new(receiver, 7, 1 << 20));
+        (UdpConnection::new(sender, 7, 1 << 20), receiver, there)
+    }
+
+    fn received(reader: &mut MessageReader<UdpConnection<TestSocket>>) -> Vec<String> {
+        reader.fetch_bytes().unwrap();
+        reader.iter().map(|frame| decode_message(&frame.unwrap()).unwrap()).collect()
+    }
+
+    #[test]
+    fn reliable_messages_survive_lost_packets() {
+        // The second chunk of the first message is lost
+        let (mut sender, mut receiver, _) = connection_pair(vec![1]);
+        let messages = ["a".repeat(3000), "b".repeat(10)];
+        for message in &messages {
+            sender.write_all(&encode_message(message).unwrap()).unwrap();
+        }
+
+        // Nothing arrives past the gap until it is resent
+        assert!(received(&mut receiver).is_empty());
+        // The receiver acks what it got while reading, so that is in order
+        sender.read(&mut [0; 16]).ok();
+        std::thread::sleep(RESEND_INTERVAL);
+        sender.flush().unwrap();
+        assert_eq!(received(&mut receiver), messages);
+
+        sender.read(&mut [0; 16]).ok();
+        assert!(sender.in_flight.is_empty());
+    }
+
+    #[test]
+    fn only_the_newest_unreliable_frame_counts() {
+        let (mut sender, mut receiver, wire) = connection_pair(vec![]);
+        for message in &["old", "new"] {
+            assert!(sender.send_unreliable(&encode_message(&message.to_string()).unwrap()).unwrap());
+        }
+        // The old one arrives last and is dropped
+        wire.borrow_mut().make_contiguous().reverse();
+        assert_eq!(received(&mut receiver), ["new"]);
+    }
+
+    #[test]
+    fn packets_of_other_connections_are_ignored() {
+        let (_sender, mut receiver, wire) = connection_pair(vec![]);
+        let forged = Packet {
+            connection: 8,
+            ack: 0,
+            body: PacketBody::Reliable { sequence: 0, data: encode_message(&"hej").unwrap() },
+        };
+        wire.borrow_mut().push_back(bincode::serialize(&forged).unwrap());
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn cookies_are_sent_back_along_with_what_was_dropped() {
+        let (mut sender, _receiver, wire) = connection_pair(vec![]);
+        sender.write_all(&encode_message(&"hej").unwrap()).unwrap();
+        let cookie = connection_cookie(&[1; 32], "127.0.0.1:5000".parse().unwrap(), 7, 0);
+        sender.receive_packet(Packet { connection: 7, ack: 0, body: PacketBody::Cookie(cookie) })
+            .unwrap();
+
+        let bodies: Vec<_> = wire.borrow().iter()
+            .map(|datagram| Packet::decode(datagram).unwrap().body)
+            .collect();
+        assert!(matches!(
+            bodies[..],
+            [
+                PacketBody::Reliable { sequence: 0, .. },
+                PacketBody::Cookie(echoed),
+                PacketBody::Reliable { sequence: 0, .. },
+            ] if echoed == cookie
+        ));
+    }
+
+    #[test]
+    fn cookies_only_fit_their_address() {
+        let address = "127.0.0.1:5000".parse().unwrap();
+        let cookie = connection_cookie(&[1; 32], address, 7, 0);
+        assert_eq!(cookie, connection_cookie(&[1; 32], address, 7, 0));
+        assert_ne!(cookie, connection_cookie(&[1; 32], "127.0.0.1:5001".parse().unwrap(), 7, 0));
+        assert_ne!(cookie, connection_cookie(&[1; 32], address, 8, 0));
+        assert_ne!(cookie, connection_cookie(&[1; 32], address, 7, 1));
+        assert_ne!(cookie, connection_cookie(&[2; 32], address, 7, 0));
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{self, Read, Write};
+
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::messages::Transport;
+
+// Browsers can not open plain TCP connections, so servers also listen for
+// WebSockets on this port
+pub const WEBSOCKET_PORT: u16 = 4447;
+
+/**
+ *  Makes a WebSocket look like a byte stream, so that the same framed
+ *  messages can be sent over it as over TCP. Every write becomes one binary
+ *  WebSocket message, and reads hand out the payloads of the binary messages
+ *  that arrive. Pings and pongs are answered by tungstenite on the way.
+ */
+pub struct WebSocketTransport<S> {
+    socket: WebSocket<S>,
+    // What is left of the last message received
+    incoming: Vec<u8>,
+}
+
+impl<S: Read + Write> WebSocketTransport<S> {
+    pub fn new(socket: WebSocket<S>) -> Self {
+        WebSocketTransport { socket, incoming: vec![] }
+    }
+}
+
+fn into_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketTransport<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.incoming.is_empty() {
+            match self.socket.read() {
+                Ok(Message::Binary(payload)) => self.incoming = payload,
+                Ok(Message::Close(_)) => return Ok(0),
+                Ok(_) => {}
+                Err(Error::ConnectionClosed) | Err(Error::AlreadyClosed) => return Ok(0),
+                Err(e) => return Err(into_io_error(e)),
+            }
+        }
+        let amount = buffer.len().min(self.incoming.len());
+        buffer[..amount].copy_from_slice(&self.incoming[..amount]);
+        self.incoming.drain(..amount);
+        Ok(amount)
+    }
+}
+
+impl<S: Read + Write> Write for WebSocketTransport<S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.socket.write(Message::Binary(data.to_vec())) {
+            Ok(()) => Ok(data.len()),
+            // The message was queued, it goes out with a later write or flush
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(data.len()),
+            Err(Error::WriteBufferFull(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(into_io_error(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush().map_err(into_io_error)
+    }
+}
+
+impl<S: Read + Write> Transport for WebSocketTransport<S> {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::messages::{decode_message, send_message, MessageReader};
+
+    #[test]
+    fn messages_get_through_websockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let socket = tungstenite::accept(stream).unwrap();
+            socket.get_ref().set_nonblocking(true).unwrap();
+            let mut reader = MessageReader::new(WebSocketTransport::new(socket));
+            loop {
+                reader.fetch_bytes().unwrap();
+                if let Some(frame) = reader.iter().next() {
+                    return decode_message::<String>(&frame.unwrap()).unwrap();
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let url = format!("ws://{}", address);
+        let (socket, _) = tungstenite::client(url.as_str(), stream).unwrap();
+        let mut transport = WebSocketTransport::new(socket);
+        send_message(&"hej".to_string(), &mut transport).unwrap();
+        assert_eq!(server.join().unwrap(), "hej");
+    }
+}
@@ -1,49 +1,335 @@
 mod assets;
 mod client_state;
+mod server_browser;
 
-use std::io::prelude::*;
-use std::net::TcpStream;
-use std::time::Instant;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use assets::Assets;
 use libplen::constants;
-use libplen::gamestate;
+use libplen::gamestate::{self, GameRules};
+use libplen::player::Player;
 use libplen::math::{vec2, Vec2};
-use libplen::messages::{ClientInput, ClientMessage, MessageReader, ServerMessage, SoundEffect};
+use libplen::messages::{
+    self, decode_message, send_message, ClientHello, ClientInput, ClientMessage, MessageError,
+    MessageReader, PasswordChallenge, PasswordProof, ServerHello, ServerMessage, Snapshot,
+    SoundEffect, Transport,
+};
+use libplen::udp::UdpConnection;
+use libplen::websocket::WebSocketTransport;
 
 use macroquad::prelude::*;
 
-fn send_client_message(msg: &ClientMessage, stream: &mut TcpStream) {
-    let data = bincode::serialize(msg).expect("Failed to encode message");
-    let length = data.len() as u16;
-    stream
-        .write(&length.to_be_bytes())
-        .expect("Failed to send message length to server");
-    stream
-        .write(&data)
-        .expect("Failed to send message to server");
+// Upper bound on how many ticks worth of input one slow frame can send
+const MAX_INPUTS_PER_FRAME: usize = 10;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long we keep trying to get back into the game after losing the
+// connection, should match the session grace period of the server
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// The server sends snapshots every tick, so this much silence means the
+// connection is dead even if the socket does not say so
+const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the room list in the lobby is refreshed
+const ROOM_LIST_INTERVAL: Duration = Duration::from_secs(2);
+
+// The server over TCP, a WebSocket or UDP, see open_transport. Send so that
+// reconnecting can happen on another thread
+type ServerStream = Box<dyn Transport + Send>;
+type Connection = MessageReader<ServerStream>;
+
+fn send_client_message(msg: &ClientMessage, stream: &mut ServerStream) -> Result<(), MessageError> {
+    send_message(msg, stream)
+}
+
+fn wait_for_frame(reader: &mut Connection) -> Result<Vec<u8>, MessageError> {
+    let start = Instant::now();
+    loop {
+        reader.fetch_bytes()?;
+        if let Some(msg) = reader.iter().next() {
+            return Ok(msg?);
+        }
+        if start.elapsed() > CONNECT_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No answer from server").into());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn wait_for_message(reader: &mut Connection) -> Result<ServerMessage, MessageError> {
+    decode_message::<ServerMessage>(&wait_for_frame(reader)?)?.unpack()
+}
+
+// Makes sure that the server speaks the same protocol as us before anything
+// else is decoded
+fn handshake(reader: &mut Connection) -> Result<(), MessageError> {
+    send_message(&ClientHello::new(), &mut reader.stream)?;
+
+    match decode_message::<ServerHello>(&wait_for_frame(reader)?).ok() {
+        Some(hello) if hello.is_compatible() => Ok(()),
+        Some(hello) if hello.is_other_version() => Err(MessageError::VersionMismatch {
+            ours: messages::PROTOCOL_VERSION,
+            theirs: hello.version,
+        }),
+        Some(ServerHello { rejection: Some(reason), .. }) => Err(MessageError::Rejected(reason)),
+        _ => Err(MessageError::Rejected("not an l2 server".into())),
+    }
+}
+
+// Connects to the server and waits for it to assign us an id, a session
+// token and, if it has a password, a challenge. The challenge is answered
+// right away, since rooms are hidden until it is
+fn connect(
+    host: &str,
+) -> Result<(Connection, u64, u64, Option<PasswordChallenge>), MessageError> {
+    let mut reader = MessageReader::new(open_transport(host)?);
+    handshake(&mut reader)?;
+
+    match wait_for_message(&mut reader)? {
+        ServerMessage::AssignId { id, token, challenge } => {
+            if let Some(proof) = answer_challenge(challenge) {
+                send_client_message(&ClientMessage::Authenticate(proof), &mut reader.stream)?;
+            }
+            Ok((reader, id, token, challenge))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Expected to get an id from server").into()),
+    }
+}
+
+// SERVER is host:port for TCP, ws://host:port for a WebSocket or
+// udp://host:port for UDP
+fn open_transport(host: &str) -> Result<ServerStream, MessageError> {
+    let (scheme, address) = host.split_once("://").unwrap_or(("tcp", host));
+    let address = address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not resolve server"))?;
+    match scheme {
+        "tcp" => {
+            let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            stream.set_nonblocking(true)?;
+            Ok(Box::new(stream))
+        }
+        "ws" => {
+            // The WebSocket handshake blocks, so it gets the same time as
+            // connecting
+            let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+            let (socket, _) = tungstenite::client(host, stream).map_err(|e| {
+                io::Error::new(io::ErrorKind::ConnectionRefused, format!("WebSocket handshake failed: {}", e))
+            })?;
+            socket.get_ref().set_nonblocking(true)?;
+            Ok(Box::new(WebSocketTransport::new(socket)))
+        }
+        "udp" => {
+            let local: SocketAddr = match address {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(address)?;
+            socket.set_nonblocking(true)?;
+            // The server is trusted with as much as over TCP
+            let buffer_limit = 2 * messages::MAX_FRAME_SIZE;
+            Ok(Box::new(UdpConnection::new(socket, ::rand::random(), buffer_limit)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown protocol {}, expected tcp, ws or udp", scheme),
+        ).into()),
+    }
+}
+
+// Answers the password challenge of the server with the PASSWORD from the
+// environment
+fn answer_challenge(challenge: Option<PasswordChallenge>) -> Option<PasswordProof> {
+    let password = std::env::var("PASSWORD").ok()?;
+    Some(messages::password_proof(&password, &challenge?))
+}
+
+// Opens a new connection and asks the server to hand it the player of our
+// old one. Returns None if the server no longer has that player
+fn resume(host: &str, token: u64) -> Result<Option<Connection>, MessageError> {
+    let (mut reader, _, _, _) = connect(host)?;
+    send_client_message(&ClientMessage::Resume { token }, &mut reader.stream)?;
+    loop {
+        match wait_for_message(&mut reader)? {
+            ServerMessage::Resumed { id } => {
+                println!("Resumed as {}", id);
+                break Ok(Some(reader));
+            }
+            ServerMessage::ResumeRejected => break Ok(None),
+            _ => {}
+        }
+    }
+}
+
+// Spectators have nothing to resume, they just start watching again on a new
+// connection
+fn respectate(host: &str, room: Option<&str>) -> Result<Connection, MessageError> {
+    let (mut reader, _, _, challenge) = connect(host)?;
+    if let Some(name) = room {
+        let join_room = ClientMessage::JoinRoom { name: name.into() };
+        enter_room(&mut reader, &join_room)?
+            .map_err(|reason| io::Error::new(io::ErrorKind::NotFound, reason))?;
+    }
+    let spectate = ClientMessage::Spectate { password: answer_challenge(challenge) };
+    send_client_message(&spectate, &mut reader.stream)?;
+    Ok(reader)
+}
+
+// The room to play in, from the environment. ROOM joins a room on the server,
+// CREATE_ROOM opens a new one with the rules in ROOM_RULES, which look like
+// "tick_rate=60,game_duration=120"
+fn room_request() -> Result<Option<ClientMessage>, String> {
+    if let Ok(name) = std::env::var("CREATE_ROOM") {
+        let mut rules = GameRules::default();
+        let settings = std::env::var("ROOM_RULES").unwrap_or_default();
+        for setting in settings.split(',').filter(|s| !s.trim().is_empty()) {
+            let (key, value) = setting.split_once('=')
+                .ok_or_else(|| format!("expected `rule=value` in ROOM_RULES, got `{}`", setting))?;
+            rules.set(key.trim(), value.trim())?;
+        }
+        rules.validate()?;
+        return Ok(Some(ClientMessage::CreateRoom { name, rules }));
+    }
+    Ok(std::env::var("ROOM").ok().map(|name| ClientMessage::JoinRoom { name }))
+}
+
+// Sends a room request and waits for the answer, which is either the name of
+// the room we are now in or why the server said no
+fn enter_room(
+    reader: &mut Connection,
+    request: &ClientMessage,
+) -> Result<Result<String, String>, MessageError> {
+    send_client_message(request, &mut reader.stream)?;
+    loop {
+        match wait_for_message(reader)? {
+            ServerMessage::JoinedRoom { name } => break Ok(Ok(name)),
+            ServerMessage::RoomError(reason) | ServerMessage::JoinRejected(reason) => {
+                break Ok(Err(reason))
+            }
+            _ => {}
+        }
+    }
+}
+
+// Shows an error until the window is closed
+async fn show_error(message: &str) -> Result<(), String> {
+    while !is_quit_requested() {
+        clear_background(BLACK);
+        draw_text(message, 20.0, 40.0, 32.0, WHITE);
+        next_frame().await;
+    }
+    Ok(())
+}
+
+async fn reconnect(
+    host: &str,
+    token: u64,
+    main_state: &mut MainState,
+    assets: &mut Assets,
+) -> Result<Connection, String> {
+    let start = Instant::now();
+    let mut last_attempt: Option<Instant> = None;
+    // Connecting blocks, so attempts run on a thread of their own while the
+    // game keeps being drawn
+    let mut attempt: Option<JoinHandle<Result<Option<Connection>, MessageError>>> = None;
+    while start.elapsed() < RECONNECT_TIMEOUT {
+        if let Some(running) = attempt.take() {
+            if !running.is_finished() {
+                attempt = Some(running);
+            } else {
+                match running.join() {
+                    Ok(Ok(Some(reader))) => return Ok(reader),
+                    Ok(Ok(None)) => return Err("The server no longer has our snake".into()),
+                    Ok(Err(e)) => println!("Could not reconnect: {}", e),
+                    Err(_) => println!("The reconnect attempt panicked"),
+                }
+            }
+        }
+        let due = last_attempt.map(|t| t.elapsed() > RECONNECT_INTERVAL).unwrap_or(true);
+        if attempt.is_none() && due {
+            last_attempt = Some(Instant::now());
+            let host = host.to_string();
+            let spectating = main_state.spectating;
+            let room = main_state.room.clone();
+            attempt = Some(thread::spawn(move || {
+                if spectating {
+                    respectate(&host, room.as_deref()).map(Some)
+                } else {
+                    resume(&host, token)
+                }
+            }));
+        }
+
+        main_state.draw(assets)?;
+        draw_text("tappade anslutningen, försöker igen...", 20.0, 40.0, 32.0, WHITE);
+        next_frame().await;
+    }
+    Err("Lost connection to server".into())
 }
 
 #[derive(PartialEq)]
 enum StateResult {
     Continue,
     GotoNext,
+    ConnectionLost,
+    JoinRejected(String),
 }
 
 struct MainState {
     my_id: u64,
+    spectating: bool,
+    // None while in the room every connection starts out in
+    room: Option<String>,
+    last_room_list: Option<Instant>,
+    join_rejection: Option<String>,
     game_state: gamestate::GameState,
+    snapshots: VecDeque<(u64, gamestate::GameState)>,
     client_state: client_state::ClientState,
     last_time: Instant,
+    last_received: Instant,
+    // The last ping we sent and when
+    last_ping: Option<(u64, Instant)>,
+    input_sequence: u64,
+    input_time: f32,
+    // Inputs that have been sent but are not yet part of a snapshot
+    pending_inputs: VecDeque<ClientInput>,
+    unsent_start_game: bool,
+    unsent_change_color: bool,
 }
 
 impl MainState {
-    fn new(my_id: u64) -> MainState {
+    fn new(my_id: u64, spectating: bool, room: Option<String>) -> MainState {
+        let mut client_state = client_state::ClientState::new();
+        if spectating {
+            client_state.enable_spectator_camera();
+        }
+        if let Some(room) = &room {
+            client_state.set_room(room.clone());
+        }
+
         MainState {
             my_id,
+            spectating,
+            room,
+            last_room_list: None,
+            join_rejection: None,
             game_state: gamestate::GameState::new(),
-            client_state: client_state::ClientState::new(),
+            snapshots: VecDeque::new(),
+            client_state,
             last_time: Instant::now(),
+            last_received: Instant::now(),
+            last_ping: None,
+            input_sequence: 0,
+            input_time: 0.,
+            pending_inputs: VecDeque::new(),
+            unsent_start_game: false,
+            unsent_change_color: false,
         }
     }
 
@@ -64,10 +350,10 @@ impl MainState {
             x_input += 1.0;
         }
 
-        ClientInput{ x_input, y_input, start_game: is_key_pressed(KeyCode::Space), change_color: is_key_pressed(KeyCode::C) }
+        ClientInput{ sequence: 0, x_input, y_input, start_game: is_key_pressed(KeyCode::Space), change_color: is_key_pressed(KeyCode::C), view: None }
     }
 
-    fn update(&mut self, server_reader: &mut MessageReader, assets: &mut Assets) -> StateResult {
+    fn update(&mut self, server_reader: &mut Connection, assets: &mut Assets) -> StateResult {
         let elapsed = self.last_time.elapsed();
         self.last_time = Instant::now();
         let dt_duration = std::time::Duration::from_millis(1000 / 60);
@@ -75,27 +361,199 @@ impl MainState {
             std::thread::sleep(dt_duration - elapsed);
         }
 
-        server_reader.fetch_bytes().unwrap();
+        let exchanged = self.exchange_messages(server_reader, elapsed.as_secs_f32(), assets);
+        // The server hangs up after turning us away, which is not worth
+        // reconnecting over
+        if let Some(reason) = self.join_rejection.take() {
+            return StateResult::JoinRejected(reason);
+        }
+        if let Err(e) = exchanged {
+            println!("Lost connection to server: {}", e);
+            return StateResult::ConnectionLost;
+        }
 
+        let predicted_player = self.predict_own_player();
+        self.client_state.update(elapsed.as_secs_f32(), predicted_player.as_ref());
+
+        StateResult::Continue
+    }
+
+    fn exchange_messages(
+        &mut self,
+        server_reader: &mut Connection,
+        elapsed: f32,
+        assets: &mut Assets,
+    ) -> Result<(), MessageError> {
+        // Messages that arrived before the connection closed are still
+        // handled, they may say why it was closed
+        let fetched = server_reader.fetch_bytes();
+
+        let mut latest_snapshot = None;
+        let mut pongs = vec![];
+        let mut game_full = false;
         for message in server_reader.iter() {
-            match bincode::deserialize(&message).unwrap() {
-                ServerMessage::AssignId(_) => panic!("Got new ID after intialisation"),
-                ServerMessage::GameState(state) => self.game_state = state,
+            self.last_received = Instant::now();
+            match decode_message::<ServerMessage>(&message?)?.unpack()? {
+                ServerMessage::AssignId { .. } => println!("Got new ID after intialisation"),
+                ServerMessage::Resumed { .. } | ServerMessage::ResumeRejected => {}
+                ServerMessage::JoinedRoom { .. } | ServerMessage::RoomError(_) => {}
+                ServerMessage::RoomList(rooms) => self.client_state.set_rooms(rooms),
+                ServerMessage::Snapshot(snapshot) => {
+                    latest_snapshot = self.apply_snapshot(snapshot).or(latest_snapshot);
+                }
                 ServerMessage::PlaySound(sound) => self.play_sound(sound, assets),
+                ServerMessage::Ping(id) => pongs.push(id),
+                ServerMessage::Pong(id) => self.receive_pong(id),
+                ServerMessage::ClockSync { sent, server_time } => {
+                    self.client_state.receive_clock_sync(sent, server_time);
+                }
+                ServerMessage::GameFull => game_full = true,
+                ServerMessage::JoinRejected(reason) => self.join_rejection = Some(reason),
+                // Unpacked into one of the above
+                ServerMessage::CompactSnapshot(_) | ServerMessage::Compressed(_) => {}
             }
         }
+        fetched?;
+
+        if self.last_received.elapsed() > SERVER_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Server stopped responding").into());
+        }
+        if game_full && !self.spectating {
+            println!("The game is full, spectating instead");
+            self.spectating = true;
+            self.client_state.enable_spectator_camera();
+            // The join got past the password check, so this needs no proof
+            let spectate = ClientMessage::Spectate { password: None };
+            send_client_message(&spectate, &mut server_reader.stream)?;
+        }
+        for id in pongs {
+            send_client_message(&ClientMessage::Pong(id), &mut server_reader.stream)?;
+        }
+        self.ping(&mut server_reader.stream)?;
+        self.request_room_list(&mut server_reader.stream)?;
 
+        if let Some(id) = latest_snapshot {
+            send_client_message(&ClientMessage::AckSnapshot(id), &mut server_reader.stream)?;
+        }
+
+        if self.spectating {
+            return Ok(());
+        }
         let input = Self::read_input();
+        self.send_inputs(input, elapsed, &mut server_reader.stream)
+    }
 
-        self.client_state
-            .update(elapsed.as_secs_f32(), &self.game_state, self.my_id);
+    fn ping(&mut self, stream: &mut ServerStream) -> Result<(), MessageError> {
+        let due = self.last_ping
+            .map(|(_, sent)| sent.elapsed().as_secs_f32() >= constants::PING_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            let id = self.last_ping.map(|(id, _)| id + 1).unwrap_or(0);
+            self.last_ping = Some((id, Instant::now()));
+            send_client_message(&ClientMessage::Ping(id), stream)?;
+            let sent = self.client_state.local_time();
+            send_client_message(&ClientMessage::SyncClock { sent }, stream)?;
+        }
+        Ok(())
+    }
+
+    // The room list is only shown in the lobby, so it is only asked for there
+    fn request_room_list(&mut self, stream: &mut ServerStream) -> Result<(), MessageError> {
+        if !matches!(self.game_state.stage, gamestate::GameStage::Lobby) {
+            return Ok(());
+        }
+        let due = self.last_room_list
+            .map(|sent| sent.elapsed() >= ROOM_LIST_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            self.last_room_list = Some(Instant::now());
+            send_client_message(&ClientMessage::ListRooms, stream)?;
+        }
+        Ok(())
+    }
 
-        let input_message = ClientMessage::Input(input);
-        send_client_message(&input_message, &mut server_reader.stream);
+    fn receive_pong(&mut self, id: u64) {
+        if let Some((ping_id, sent)) = self.last_ping {
+            if ping_id == id {
+                self.client_state.set_ping(sent.elapsed());
+            }
+        }
+    }
 
-        StateResult::Continue
+    // The server uses one input per tick, so one is sent for every tick that
+    // has passed since the last frame
+    fn send_inputs(
+        &mut self,
+        mut input: ClientInput,
+        elapsed: f32,
+        stream: &mut ServerStream,
+    ) -> Result<(), MessageError> {
+        self.unsent_start_game |= input.start_game;
+        self.unsent_change_color |= input.change_color;
+
+        input.view = self.client_state.viewed_snapshot();
+        let delta_time = self.game_state.rules.delta_time();
+        let max_input_time = MAX_INPUTS_PER_FRAME as f32 * delta_time;
+        self.input_time = (self.input_time + elapsed).min(max_input_time);
+        while self.input_time >= delta_time {
+            self.input_time -= delta_time;
+            self.input_sequence += 1;
+
+            input.sequence = self.input_sequence;
+            input.start_game = self.unsent_start_game;
+            input.change_color = self.unsent_change_color;
+            self.unsent_start_game = false;
+            self.unsent_change_color = false;
+
+            send_client_message(&ClientMessage::Input(input.clone()), stream)?;
+            self.pending_inputs.push_back(input.clone());
+        }
+        Ok(())
+    }
+
+    // Replays the inputs the server has not seen yet on top of the last
+    // authoritative state of our own player
+    fn predict_own_player(&self) -> Option<Player> {
+        if !matches!(self.game_state.stage, gamestate::GameStage::Running) {
+            return None;
+        }
+
+        let mut player = self.game_state.get_player_by_id(self.my_id)?.clone();
+        for input in &self.pending_inputs {
+            player.set_input(input.x_input, input.y_input, input.start_game, input.change_color);
+            player.update(self.game_state.rules.delta_time());
+        }
+        Some(player)
     }
     
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Option<u64> {
+        let mut state = match snapshot.baseline {
+            Some(baseline) => match self.snapshots.iter().find(|(id, _)| *id == baseline) {
+                Some((_, state)) => state.clone(),
+                None => {
+                    println!("Got a delta from unknown snapshot {}", baseline);
+                    return None;
+                }
+            },
+            None => gamestate::GameState::new(),
+        };
+        snapshot.delta.apply_to(&mut state);
+
+        // The server only moves its baseline forward, so anything older than
+        // the current one will never be needed again
+        self.snapshots.retain(|(id, _)| Some(*id) >= snapshot.baseline);
+        self.snapshots.push_back((snapshot.id, state.clone()));
+        self.client_state.push_snapshot(snapshot.id, snapshot.server_time, &state);
+        while self.snapshots.len() > constants::SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+
+        self.pending_inputs.retain(|input| input.sequence > snapshot.last_processed_input);
+
+        self.game_state = state;
+        Some(snapshot.id)
+    }
+
     pub fn play_sound(&self, sound: SoundEffect, assets: &Assets) {
         match sound {
             SoundEffect::Welcome => macroquad::audio::play_sound_once(assets.welcome),
@@ -117,48 +575,90 @@ impl MainState {
 
 #[macroquad::main("l2")]
 async fn main() -> Result<(), String> {
-    let host = std::env::var("SERVER").unwrap_or(String::from("localhost:4444"));
-    let stream = TcpStream::connect(host).expect("Could not connect to server");
-    println!("Connected to server");
-
-    stream
-        .set_nonblocking(true)
-        .expect("Could not set socket as nonblocking");
-    let mut reader = MessageReader::new(stream);
-
-    let msg = loop {
-        reader.fetch_bytes().unwrap();
-        if let Some(msg) = reader.iter().next() {
-            break bincode::deserialize(&msg).unwrap();
+    // Without a SERVER the player picks one on the local network
+    let host = match std::env::var("SERVER") {
+        Ok(host) => host,
+        Err(_) => match server_browser::pick_server().await {
+            Ok(Some(address)) => address.to_string(),
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                println!("Could not look for servers: {}", e);
+                return show_error(&format!("kunde inte söka efter servrar: {}", e)).await;
+            }
+        },
+    };
+    let (mut reader, my_id, token, challenge) = match connect(&host) {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("Could not connect to server: {}", e);
+            return show_error(&e.to_string()).await;
         }
     };
-    let mut assets = assets::Assets::new();
+    println!("Connected to server");
+    println!("Received the id {}", my_id);
 
-    let my_id = if let ServerMessage::AssignId(id) = msg {
-        println!("Received the id {}", id);
-        id
-    } else {
-        panic!("Expected to get an id from server")
+    let room_request = match room_request() {
+        Ok(request) => request,
+        Err(e) => return show_error(&e).await,
+    };
+    let room = match room_request.map(|request| enter_room(&mut reader, &request)) {
+        Some(Ok(Ok(room))) => Some(room),
+        Some(Ok(Err(reason))) => return show_error(&format!("kom inte in i rummet: {}", reason)).await,
+        Some(Err(e)) => return show_error(&e.to_string()).await,
+        None => None,
     };
 
-    let mut main_state = MainState::new(my_id);
+    let mut assets = assets::Assets::new();
+    // Closing the window tells the server that we are leaving, rather than
+    // having our snake wait for us to come back
+    prevent_quit();
+
+    let spectating = std::env::var("SPECTATE").is_ok();
+    let mut main_state = MainState::new(my_id, spectating, room);
 
     let name = whoami::username();
 
     loop {
 
-        send_client_message(
-            &ClientMessage::JoinGame {
-                name
-            },
-            &mut reader.stream,
-        );
+        let password = answer_challenge(challenge);
+        let join_message = if spectating {
+            ClientMessage::Spectate { password }
+        } else {
+            ClientMessage::JoinGame {
+                name,
+                password,
+            }
+        };
+        // A failed send is noticed as a lost connection on the next update
+        if let Err(e) = send_client_message(&join_message, &mut reader.stream) {
+            println!("Could not join the game: {}", e);
+        }
 
         main_state.play_sound(SoundEffect::Welcome, &assets);
 
         loop {
+            if is_quit_requested() {
+                if let Err(e) = send_client_message(&ClientMessage::Leave, &mut reader.stream) {
+                    println!("Could not say goodbye to the server: {}", e);
+                }
+                return Ok(());
+            }
 
-            main_state.update(&mut reader, &mut assets);
+            match main_state.update(&mut reader, &mut assets) {
+                StateResult::ConnectionLost => {
+                    reader = match reconnect(&host, token, &mut main_state, &mut assets).await {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            return show_error(&format!("tappade anslutningen: {}", e)).await;
+                        }
+                    };
+                    main_state.last_received = Instant::now();
+                }
+                StateResult::JoinRejected(reason) => {
+                    return show_error(&format!("kom inte in i spelet: {}", reason)).await;
+                }
+                _ => {}
+            }
 
             main_state.draw(&mut assets)?;
 
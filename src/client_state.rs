@@ -1,6 +1,12 @@
+use std::collections::VecDeque;
+use std::mem::{self, Discriminant};
+use std::time::{Duration, Instant};
+
+use libplen::clock::ClockSync;
 use libplen::constants;
-use libplen::gamestate::GameState;
+use libplen::gamestate::{GameStage, GameState};
 use libplen::math::{self, vec2, Vec2};
+use libplen::messages::RoomInfo;
 use libplen::player::Player;
 use macroquad::math::vec2 as macroquad_vec2;
 use macroquad::prelude::*;
@@ -15,14 +21,50 @@ const FOOD_SIZE: f32 = 5.0;
 const PLAYER_MENU_SPACING: f32 = 80.0;
 const PLAYER_MENU_Y: f32 = constants::WINDOW_SIZE - 100.0;
 const LEADERBOARD_SNAKE_SCALE: f32 = 0.5;
+// If the render clock is further than this from where it should be it is
+// reset instead of slowly corrected
+const MAX_RENDER_TIME_ERROR: f64 = 0.25;
+const RENDER_TIME_CORRECTION: f64 = 0.1;
+// Anything that moved further than this between two snapshots was teleported
+// rather than moved, and is not interpolated
+const MAX_INTERPOLATION_DISTANCE: f32 = 30.0;
+const CAMERA_SPEED: f32 = 400.0;
+const CAMERA_ZOOM_SPEED: f32 = 1.5;
+const CAMERA_MIN_ZOOM: f32 = 0.5;
+const CAMERA_MAX_ZOOM: f32 = 4.0;
+const PLAYER_LIST_Y: f32 = 400.0;
+const ROOM_LIST_Y: f32 = 50.0;
 
 const COLORS: [macroquad::color::Color; 11] = [
     RED, GREEN, PURPLE, ORANGE, PINK, VIOLET, MAGENTA, LIME, BROWN, GOLD, WHITE
 ];
 
 
+// The spectator camera either moves freely or stays on the head of a player
+pub enum Camera {
+    Free { center: Vec2, zoom: f32 },
+    Follow { player_id: u64, zoom: f32 },
+}
+
 pub struct ClientState {
     screen_scale: f32,
+    // Only spectators have a camera, players always see the whole arena
+    camera: Option<Camera>,
+    render_delay: f64,
+    render_time: f64,
+    // Snapshots along with the server time they were taken at and their id
+    snapshots: VecDeque<(f64, u64, GameState)>,
+    interpolated: Option<GameState>,
+    ping: Option<Duration>,
+    clock: ClockSync,
+    // Our clock starts with the client
+    started: Instant,
+    // The timer of the newest snapshot along with the server time it was
+    // taken at and the stage it was for, to count down from between snapshots
+    timer: Option<(f64, f32, Discriminant<GameStage>)>,
+    // The rooms of the server, shown in the lobby
+    rooms: Vec<RoomInfo>,
+    room: Option<String>,
 }
 
 impl ClientState {
@@ -31,13 +73,194 @@ impl ClientState {
             Ok(val) => val.parse::<f32>().unwrap(),
             Err(_) => 1.0,
         };
+        let render_delay = match std::env::var("RENDER_DELAY") {
+            Ok(val) => val.parse::<f64>().unwrap() / 1000.,
+            Err(_) => constants::RENDER_DELAY as f64,
+        };
         ClientState {
-            screen_scale
+            screen_scale,
+            camera: None,
+            render_delay,
+            render_time: 0.,
+            snapshots: VecDeque::new(),
+            interpolated: None,
+            ping: None,
+            clock: ClockSync::new(),
+            started: Instant::now(),
+            timer: None,
+            rooms: vec![],
+            room: None,
+        }
+    }
+
+    pub fn set_ping(&mut self, ping: Duration) {
+        self.ping = Some(ping);
+    }
+
+    pub fn local_time(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    pub fn receive_clock_sync(&mut self, sent: f64, server_time: f64) {
+        let received = self.local_time();
+        self.clock.add_sample(sent, server_time, received);
+    }
+
+    // Seconds left of the game or the countdown before it, counted down on
+    // the server clock rather than jumping with every snapshot
+    fn time_left(&self, game_state: &GameState) -> f32 {
+        let now = self.clock.server_time(self.local_time());
+        let stage = mem::discriminant(&game_state.stage);
+        match (self.timer, now) {
+            // What is drawn is a little behind the newest snapshot, which may
+            // already be counting down something else
+            (Some((taken, timer, timer_stage)), Some(now)) if timer_stage == stage => {
+                (timer - (now - taken) as f32).max(0.)
+            }
+            _ => game_state.game_timer,
+        }
+    }
+
+    pub fn set_rooms(&mut self, rooms: Vec<RoomInfo>) {
+        self.rooms = rooms;
+    }
+
+    pub fn set_room(&mut self, room: String) {
+        self.room = Some(room);
+    }
+
+    pub fn enable_spectator_camera(&mut self) {
+        self.camera = Some(Camera::Free {
+            center: vec2(constants::WINDOW_SIZE / 2., constants::WINDOW_SIZE / 2.),
+            zoom: 1.,
+        });
+    }
+
+    fn camera_center(&self, game_state: &GameState) -> Option<(Vec2, f32)> {
+        match self.camera.as_ref()? {
+            Camera::Free { center, zoom } => Some((*center, *zoom)),
+            Camera::Follow { player_id, zoom } => {
+                let player = game_state.get_player_by_id(*player_id)?;
+                Some((player.get_head_position(), *zoom))
+            }
         }
     }
 
-    pub fn update(&mut self, delta_time: f32, game_state: &GameState, my_id: u64) {
-        // update client side stuff
+    // Tab cycles through the players to follow, F releases the camera which
+    // is then moved with WASD or the arrow keys. Q and E zoom.
+    fn update_camera(&mut self, delta_time: f32) {
+        let game_state = match &self.interpolated {
+            Some(game_state) => game_state,
+            None => return,
+        };
+        let (center, zoom) = match self.camera_center(game_state) {
+            Some(view) => view,
+            None => (vec2(constants::WINDOW_SIZE / 2., constants::WINDOW_SIZE / 2.), 1.),
+        };
+
+        let mut zoom = zoom;
+        if is_key_down(KeyCode::E) {
+            zoom *= CAMERA_ZOOM_SPEED.powf(delta_time);
+        }
+        if is_key_down(KeyCode::Q) {
+            zoom /= CAMERA_ZOOM_SPEED.powf(delta_time);
+        }
+        let zoom = zoom.clamp(CAMERA_MIN_ZOOM, CAMERA_MAX_ZOOM);
+
+        let followed = match self.camera {
+            Some(Camera::Follow { player_id, .. }) => Some(player_id),
+            _ => None,
+        };
+
+        self.camera = if is_key_pressed(KeyCode::Tab) && !game_state.players.is_empty() {
+            let next = followed
+                .and_then(|id| game_state.players.iter().position(|p| p.id == id))
+                .map(|i| (i + 1) % game_state.players.len())
+                .unwrap_or(0);
+            Some(Camera::Follow { player_id: game_state.players[next].id, zoom })
+        } else if followed.is_some() && !is_key_pressed(KeyCode::F) {
+            Some(Camera::Follow { player_id: followed.unwrap(), zoom })
+        } else {
+            let mut direction = vec2(0., 0.);
+            if is_key_down(KeyCode::W) || is_key_down(KeyCode::Up) {
+                direction.y -= 1.;
+            }
+            if is_key_down(KeyCode::S) || is_key_down(KeyCode::Down) {
+                direction.y += 1.;
+            }
+            if is_key_down(KeyCode::A) || is_key_down(KeyCode::Left) {
+                direction.x -= 1.;
+            }
+            if is_key_down(KeyCode::D) || is_key_down(KeyCode::Right) {
+                direction.x += 1.;
+            }
+            let center = center + direction * CAMERA_SPEED / zoom * delta_time;
+            Some(Camera::Free { center, zoom })
+        };
+    }
+
+    pub fn push_snapshot(&mut self, snapshot_id: u64, server_time: f64, game_state: &GameState) {
+        // Time only goes backwards if we are talking to a restarted server
+        if self.snapshots.back().map(|(time, _, _)| *time > server_time).unwrap_or(false) {
+            self.snapshots.clear();
+        }
+        self.snapshots.push_back((server_time, snapshot_id, game_state.clone()));
+        let stage = mem::discriminant(&game_state.stage);
+        self.timer = Some((server_time, game_state.game_timer, stage));
+    }
+
+    // The snapshot closest to what is drawn, which the server judges our
+    // cuts against
+    pub fn viewed_snapshot(&self) -> Option<u64> {
+        let (from_time, from_id, _) = self.snapshots.front()?;
+        let closest = match self.snapshots.get(1) {
+            Some((to_time, to_id, _)) if to_time + from_time < 2. * self.render_time => to_id,
+            _ => from_id,
+        };
+        Some(*closest)
+    }
+
+    pub fn update(&mut self, delta_time: f32, predicted_player: Option<&Player>) {
+        let newest = match self.snapshots.back() {
+            Some((time, _, _)) => *time,
+            None => return,
+        };
+
+        let target = newest - self.render_delay;
+        self.render_time += delta_time as f64;
+        if (self.render_time - target).abs() > MAX_RENDER_TIME_ERROR {
+            self.render_time = target;
+        } else {
+            self.render_time += (target - self.render_time) * RENDER_TIME_CORRECTION;
+        }
+
+        // Keep the newest snapshot that is older than the render time
+        while self.snapshots.len() >= 2 && self.snapshots[1].0 <= self.render_time {
+            self.snapshots.pop_front();
+        }
+
+        let (from_time, _, from) = &self.snapshots[0];
+        self.interpolated = match self.snapshots.get(1) {
+            Some((to_time, _, to)) if self.render_time > *from_time => {
+                let t = (self.render_time - from_time) / (to_time - from_time);
+                Some(interpolate(from, to, t as f32))
+            }
+            _ => Some(from.clone()),
+        };
+
+        if self.camera.is_some() {
+            self.update_camera(delta_time);
+        }
+
+        // Our own snake is drawn where we predict it to be rather than
+        // where it was a render delay ago
+        if let (Some(state), Some(predicted)) = (&mut self.interpolated, predicted_player) {
+            for player in &mut state.players {
+                if player.id == predicted.id {
+                    *player = predicted.clone();
+                }
+            }
+        }
     }
 
     pub fn draw(
@@ -46,24 +269,52 @@ impl ClientState {
         game_state: &GameState,
         assets: &mut Assets,
     ) -> Result<(), String> {
+        let game_state = self.interpolated.as_ref().unwrap_or(game_state);
 
         clear_background(BLACK);
-        self.draw_bounds();
+        let running = matches!(game_state.stage, libplen::gamestate::GameStage::Running);
+        // The spectator camera draws its own bounds where the arena is
+        if self.camera.is_none() || !running {
+            self.draw_bounds();
+        }
 
         match game_state.stage {
             libplen::gamestate::GameStage::Lobby => {
                 self.draw_menu(game_state, assets);
+                self.draw_room_list();
             }
             libplen::gamestate::GameStage::Running => {
+                if let Some((center, zoom)) = self.camera_center(game_state) {
+                    let width = screen_width() / zoom;
+                    let height = screen_height() / zoom;
+                    set_camera(&Camera2D::from_display_rect(Rect::new(
+                        center.x * self.screen_scale - width / 2.,
+                        center.y * self.screen_scale - height / 2.,
+                        width,
+                        height,
+                    )));
+                    self.draw_bounds();
+                }
                 self.draw_players(&game_state.players, my_id);
                 self.draw_food(&game_state.food);
+                set_default_camera();
+
                 self.draw_progress_bar(game_state);
                 self.draw_leaderboard(game_state);
+                if self.camera.is_some() {
+                    self.draw_player_list(game_state);
+                }
             }
             libplen::gamestate::GameStage::Ended => {
                 self.draw_end_screen(game_state);
             }
+            libplen::gamestate::GameStage::Starting => {
+                self.draw_players(&game_state.players, my_id);
+                self.draw_food(&game_state.food);
+                self.draw_countdown(game_state);
+            }
         }
+        self.draw_ping();
 
         Ok(())
     }
@@ -94,8 +345,68 @@ impl ClientState {
     }
 
 
+    fn draw_player_list(&self, game_state: &GameState) {
+        let followed = match self.camera {
+            Some(Camera::Follow { player_id, .. }) => Some(player_id),
+            _ => None,
+        };
+
+        for (i, player) in game_state.players.iter().enumerate() {
+            let marker = if Some(player.id) == followed { "> " } else { "" };
+            let text = format!("{}{} ({})", marker, player.name, player.snake.len());
+            draw_text(
+                &text,
+                (constants::WINDOW_SIZE + 20.0) * self.screen_scale,
+                (PLAYER_LIST_Y + 30.0 * (i as f32)) * self.screen_scale,
+                24.0 * self.screen_scale,
+                COLORS[player.color % COLORS.len()],
+            );
+        }
+    }
+
+    fn draw_room_list(&self) {
+        if self.rooms.is_empty() {
+            return;
+        }
+        draw_text(
+            "rum",
+            (constants::WINDOW_SIZE + 20.0) * self.screen_scale,
+            ROOM_LIST_Y * self.screen_scale,
+            24.0 * self.screen_scale,
+            WHITE,
+        );
+        for (i, room) in self.rooms.iter().enumerate() {
+            // Until we have joined a room we are in the first one, which is
+            // the room of the server config
+            let current = match &self.room {
+                Some(name) => *name == room.name,
+                None => i == 0,
+            };
+            let marker = if current { "> " } else { "" };
+            let text = format!("{}{} ({}/{})", marker, room.name, room.players, room.max_players);
+            draw_text(
+                &text,
+                (constants::WINDOW_SIZE + 20.0) * self.screen_scale,
+                (ROOM_LIST_Y + 30.0 * (i as f32 + 1.0)) * self.screen_scale,
+                20.0 * self.screen_scale,
+                if current { WHITE } else { GRAY },
+            );
+        }
+    }
+
+    fn draw_countdown(&self, game_state: &GameState) {
+        let seconds = self.time_left(game_state).ceil().max(1.);
+        draw_text(
+            &format!("{}", seconds),
+            (constants::WINDOW_SIZE / 2.0 - 20.0) * self.screen_scale,
+            (constants::WINDOW_SIZE / 2.0) * self.screen_scale,
+            120.0 * self.screen_scale,
+            WHITE,
+        );
+    }
+
     fn draw_progress_bar(&self, game_state: &GameState) {
-        let progress = game_state.game_timer / constants::GAME_DURATION;
+        let progress = self.time_left(game_state) / game_state.rules.game_duration;
         let width = constants::WINDOW_SIZE * progress * self.screen_scale;
         draw_rectangle(
             0.0,
@@ -107,6 +418,20 @@ impl ClientState {
     }
 
 
+    fn draw_ping(&self) {
+        let text = match self.ping {
+            Some(ping) => format!("ping: {} ms", ping.as_millis()),
+            None => "ping: ?".to_string(),
+        };
+        draw_text(
+            &text,
+            (constants::WINDOW_SIZE + 20.0) * self.screen_scale,
+            (constants::WINDOW_SIZE - 20.0) * self.screen_scale,
+            20.0 * self.screen_scale,
+            GRAY,
+        );
+    }
+
     fn draw_end_screen(&self, game_state: &GameState) {
         let text1 = "då var spelet slut";
         let text2 = "tryck space för att börja om";
@@ -177,6 +502,12 @@ impl ClientState {
     fn draw_players(&self, players: &Vec<Player>, my_id: u64) {
         for player in players {
             let color = COLORS[player.color % COLORS.len()];
+            // Players waiting for their connection to come back are faded out
+            let color = if player.frozen {
+                Color::new(color.r, color.g, color.b, 0.3)
+            } else {
+                color
+            };
 
             let head_px = player.snake.segments[0].position.x;
             let head_py = player.snake.segments[0].position.y;
@@ -184,7 +515,7 @@ impl ClientState {
             draw_circle(head_px * self.screen_scale, head_py * self.screen_scale,
                 5.0 * self.screen_scale, color);
 
-            let body_color = Color::new(color.r, color.g, color.b, 0.9);
+            let body_color = Color::new(color.r, color.g, color.b, 0.9 * color.a);
 
             for i in 0..(player.snake.segments.len() - 1) {
                 let curr = &player.snake.segments[i];
@@ -250,7 +581,44 @@ impl ClientState {
                         macroquad_vec2(v3.x, v3.y) * self.screen_scale,
                         BLUE);
                 },
+                FoodType::Hidden => {}
             };
         }
     }
 }
+
+
+fn interpolate_position(from: Vec2, to: Vec2, t: f32) -> Vec2 {
+    let difference = math::wrapped_difference(from, to, constants::WINDOW_SIZE);
+    if difference.norm() > MAX_INTERPOLATION_DISTANCE {
+        to
+    } else {
+        math::lerp_wrap_around(from, to, t, constants::WINDOW_SIZE)
+    }
+}
+
+fn interpolate(from: &GameState, to: &GameState, t: f32) -> GameState {
+    let mut result = to.clone();
+    result.game_timer = from.game_timer + (to.game_timer - from.game_timer) * t;
+
+    for player in &mut result.players {
+        let old_player = match from.get_player_by_id(player.id) {
+            Some(old_player) => old_player,
+            None => continue,
+        };
+        for (segment, old_segment) in player.snake.segments.iter_mut()
+            .zip(&old_player.snake.segments) {
+            segment.position = interpolate_position(old_segment.position, segment.position, t);
+        }
+    }
+
+    // Food keeps its index, but may have come into view since the last state
+    for (food, old_food) in result.food.iter_mut().zip(&from.food) {
+        if food.is_hidden() || old_food.is_hidden() {
+            continue;
+        }
+        food.position = interpolate_position(old_food.position, food.position, t);
+    }
+
+    result
+}
@@ -0,0 +1,392 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+
+use libplen::messages::{decode_message_with_limit, encode_message, MessageError, MessageReader};
+use libplen::registry::{
+    RegistryReply, RegistryRequest, ServerListing, REGISTER_INTERVAL, REGISTRY_PORT,
+};
+
+const USAGE: &str = "\
+usage: registry [--address ADDRESS] [--port PORT]
+
+Keeps a list of the game servers that register with it, for clients to
+browse. Listens on 0.0.0.0:4446 by default.";
+
+const LISTENER: Token = Token(usize::MAX);
+// Servers are forgotten after missing this many registrations in a row
+const MISSED_REGISTRATIONS: u32 = 3;
+// Requests are small, a connection that sends anything larger is dropped
+const MAX_REQUEST_SIZE: usize = 4096;
+// A connection only carries one request, so there is no reason for it to
+// stay open long
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CONNECTIONS: usize = 256;
+const MAX_SERVERS: usize = 1024;
+// Keeps one machine from filling up the list
+const MAX_SERVERS_PER_ADDRESS: usize = 16;
+const MAX_NAME_LENGTH: usize = 32;
+
+struct Connection {
+    token: Token,
+    peer: SocketAddr,
+    reader: MessageReader<TcpStream>,
+    outbox: Vec<u8>,
+    opened: Instant,
+    // The connection is closed once the reply to its request is sent
+    answered: bool,
+    error: Option<MessageError>,
+}
+
+impl Connection {
+    fn receive(&mut self) {
+        if let Err(e) = self.reader.fetch_bytes() {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn flush(&mut self) {
+        while !self.outbox.is_empty() && self.error.is_none() {
+            match self.reader.stream.write(&self.outbox) {
+                Ok(0) => self.error = Some(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => {
+                    self.outbox.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => self.error = Some(e.into()),
+            }
+        }
+    }
+}
+
+struct Entry {
+    listing: ServerListing,
+    last_seen: Instant,
+}
+
+struct Registry {
+    listener: TcpListener,
+    poll: Poll,
+    events: Events,
+    connections: Vec<Connection>,
+    next_token: usize,
+    servers: Vec<Entry>,
+    // How long a server stays listed after its last registration
+    lifetime: Duration,
+}
+
+impl Registry {
+    fn new(address: SocketAddr) -> io::Result<Registry> {
+        let mut listener = TcpListener::bind(address)?;
+        let poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        println!("Listening on {}", listener.local_addr()?);
+
+        Ok(Registry {
+            listener,
+            poll,
+            events: Events::with_capacity(1024),
+            connections: vec![],
+            next_token: 0,
+            servers: vec![],
+            lifetime: REGISTER_INTERVAL * MISSED_REGISTRATIONS,
+        })
+    }
+
+    fn update(&mut self, timeout: Duration) {
+        if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+            if e.kind() != io::ErrorKind::Interrupted {
+                println!("Failed to poll sockets: {}", e);
+            }
+            return;
+        }
+
+        let events: Vec<_> = self.events.iter()
+            .map(|e| (e.token(), e.is_readable() || e.is_read_closed(), e.is_writable()))
+            .collect();
+        for (token, readable, writable) in events {
+            if token == LISTENER {
+                self.accept_new_connections();
+                continue;
+            }
+            if let Some(connection) = self.connections.iter_mut().find(|c| c.token == token) {
+                if readable {
+                    connection.receive();
+                }
+                if writable {
+                    connection.flush();
+                }
+            }
+        }
+
+        self.expire_servers();
+        self.answer_requests();
+        self.close_connections();
+    }
+
+    fn accept_new_connections(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    if self.connections.len() >= MAX_CONNECTIONS {
+                        println!("Turning {} away, too many open connections", peer);
+                        continue;
+                    }
+                    let token = Token(self.next_token);
+                    self.next_token = (self.next_token + 1) % LISTENER.0;
+                    let mut connection = Connection {
+                        token,
+                        peer,
+                        reader: MessageReader::with_buffer_limit(stream, 4 + MAX_REQUEST_SIZE),
+                        outbox: vec![],
+                        opened: Instant::now(),
+                        answered: false,
+                        error: None,
+                    };
+                    let registered = self.poll.registry().register(
+                        &mut connection.reader.stream,
+                        token,
+                        Interest::READABLE | Interest::WRITABLE,
+                    );
+                    match registered {
+                        Ok(()) => self.connections.push(connection),
+                        Err(e) => println!("Could not register connection from {}: {}", peer, e),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Could not accept connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn answer_requests(&mut self) {
+        for connection in self.connections.iter_mut() {
+            if connection.answered || connection.error.is_some() {
+                continue;
+            }
+            let frame = match connection.reader.iter().next() {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    connection.error = Some(e.into());
+                    continue;
+                }
+                None => continue,
+            };
+            let reply = match decode_message_with_limit(&frame, MAX_REQUEST_SIZE as u64) {
+                Ok(request) => Self::answer(&mut self.servers, connection.peer, request),
+                Err(e) => {
+                    connection.error = Some(e);
+                    continue;
+                }
+            };
+            match encode_message(&reply) {
+                Ok(reply) => connection.outbox = reply,
+                Err(e) => connection.error = Some(e),
+            }
+            connection.answered = true;
+            connection.flush();
+        }
+    }
+
+    fn answer(servers: &mut Vec<Entry>, peer: SocketAddr, request: RegistryRequest) -> RegistryReply {
+        let (announcement, rules) = match request {
+            RegistryRequest::List => {
+                let listings = servers.iter().map(|entry| entry.listing.clone()).collect();
+                return RegistryReply::Servers(listings);
+            }
+            RegistryRequest::Register { announcement, rules } => (announcement, rules),
+        };
+
+        let name = announcement.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            let reason = format!("Names must be 1 to {} characters long", MAX_NAME_LENGTH);
+            return RegistryReply::Rejected(reason);
+        }
+        if let Err(e) = rules.validate() {
+            return RegistryReply::Rejected(e);
+        }
+
+        let address = SocketAddr::new(peer.ip(), announcement.port);
+        let listing = ServerListing { address, announcement, rules };
+        if let Some(entry) = servers.iter_mut().find(|entry| entry.listing.address == address) {
+            entry.listing = listing;
+            entry.last_seen = Instant::now();
+            return RegistryReply::Registered;
+        }
+
+        let from_peer = servers.iter()
+            .filter(|entry| entry.listing.address.ip() == peer.ip())
+            .count();
+        if from_peer >= MAX_SERVERS_PER_ADDRESS {
+            return RegistryReply::Rejected(format!("Too many servers on {}", peer.ip()));
+        }
+        if servers.len() >= MAX_SERVERS {
+            return RegistryReply::Rejected("The registry is full".into());
+        }
+        println!("Registered {} at {}", listing.announcement.name, address);
+        servers.push(Entry { listing, last_seen: Instant::now() });
+        RegistryReply::Registered
+    }
+
+    fn expire_servers(&mut self) {
+        let lifetime = self.lifetime;
+        self.servers.retain(|entry| {
+            let alive = entry.last_seen.elapsed() <= lifetime;
+            if !alive {
+                println!("Forgot {} at {}", entry.listing.announcement.name, entry.listing.address);
+            }
+            alive
+        });
+    }
+
+    fn close_connections(&mut self) {
+        self.connections.retain(|connection| {
+            if let Some(e) = &connection.error {
+                if !e.is_disconnect() {
+                    println!("Dropping connection from {}: {}", connection.peer, e);
+                }
+                return false;
+            }
+            if connection.opened.elapsed() > CONNECTION_TIMEOUT {
+                println!("Dropping connection from {}: too slow", connection.peer);
+                return false;
+            }
+            !(connection.answered && connection.outbox.is_empty())
+        });
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<SocketAddr, String> {
+    let mut address = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let mut port = REGISTRY_PORT;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--address" => {
+                address = value.parse().map_err(|_| format!("expected an IP address, got `{}`", value))?
+            }
+            "--port" => {
+                port = value.parse().map_err(|_| format!("expected a port number, got `{}`", value))?
+            }
+            _ => return Err(format!("unexpected argument `{}`\n\n{}", arg, USAGE)),
+        }
+    }
+    Ok(SocketAddr::new(address, port))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let address = match parse_args(args.into_iter()) {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut registry = match Registry::new(address) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+    loop {
+        registry.update(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libplen::discovery::ServerAnnouncement;
+    use libplen::gamestate::{GameRules, GameStage};
+    use libplen::registry;
+
+    fn test_registry() -> Registry {
+        Registry::new("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    // Sends a request from another thread, as a server or client would, and
+    // runs the registry until it is answered
+    fn send(registry: &mut Registry, request: RegistryRequest) -> RegistryReply {
+        let address = registry.listener.local_addr().unwrap().to_string();
+        let sender = std::thread::spawn(move || registry::request(&address, &request));
+        while !sender.is_finished() {
+            registry.update(Duration::from_millis(5));
+        }
+        sender.join().unwrap().unwrap()
+    }
+
+    fn register(registry: &mut Registry, name: &str, port: u16) -> RegistryReply {
+        let announcement = ServerAnnouncement {
+            name: name.into(),
+            version: 1,
+            players: 2,
+            max_players: 8,
+            stage: GameStage::Lobby,
+            port,
+            password: false,
+        };
+        let rules = GameRules { tick_rate: 60, ..GameRules::default() };
+        send(registry, RegistryRequest::Register { announcement, rules })
+    }
+
+    fn list(registry: &mut Registry) -> Vec<ServerListing> {
+        match send(registry, RegistryRequest::List) {
+            RegistryReply::Servers(servers) => servers,
+            _ => panic!("Expected a list of servers"),
+        }
+    }
+
+    #[test]
+    fn registered_servers_are_listed() {
+        let mut registry = test_registry();
+        assert!(matches!(register(&mut registry, "kontoret", 4444), RegistryReply::Registered));
+        // Registering again updates the listing instead of adding another
+        assert!(matches!(register(&mut registry, "köket", 4444), RegistryReply::Registered));
+
+        let servers = list(&mut registry);
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].address, "127.0.0.1:4444".parse().unwrap());
+        assert_eq!(servers[0].announcement.name, "köket");
+        assert_eq!(servers[0].rules.tick_rate, 60);
+        assert!(registry.connections.is_empty());
+    }
+
+    #[test]
+    fn silent_servers_are_forgotten() {
+        let mut registry = test_registry();
+        registry.lifetime = Duration::ZERO;
+        register(&mut registry, "kontoret", 4444);
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(list(&mut registry).is_empty());
+    }
+
+    #[test]
+    fn bad_registrations_are_rejected() {
+        let mut registry = test_registry();
+        let reply = register(&mut registry, &"x".repeat(MAX_NAME_LENGTH + 1), 4444);
+        assert!(matches!(reply, RegistryReply::Rejected(_)));
+
+        for port in 0..MAX_SERVERS_PER_ADDRESS as u16 {
+            register(&mut registry, "flood", port);
+        }
+        assert!(matches!(register(&mut registry, "flood", 9999), RegistryReply::Rejected(_)));
+        assert_eq!(list(&mut registry).len(), MAX_SERVERS_PER_ADDRESS);
+    }
+}
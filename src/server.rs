@@ -1,155 +1,989 @@
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::time::Instant;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::vec;
 
+use mio::net::{TcpListener, UdpSocket};
+use mio::{Events, Interest, Poll, Token};
 use unicode_truncate::UnicodeTruncateStr;
 
 use libplen::constants;
-use libplen::gamestate;
+use libplen::discovery::{is_discovery_request, ServerAnnouncement, DISCOVERY_PACKET_SIZE};
+use libplen::registry::RegistryRequest;
+use libplen::gamestate::{self, GameRules};
 use libplen::math::{vec2, Vec2};
-use libplen::messages::{ClientInput, ClientMessage, MessageReader, ServerMessage, SoundEffect};
+use libplen::compact::CompactDelta;
+use libplen::messages::{
+    check_password_proof, decode_message_with_features, decode_message_with_limit,
+    encode_message, encode_server_message, ClientHello, ClientInput, ClientMessage,
+    CompactSnapshot, MessageError, MessageReader, PasswordChallenge, PasswordProof, ServerHello,
+    ServerMessage, Snapshot, Transport, FEATURE_COMPACT_STATE, PROTOCOL_VERSION,
+};
 use libplen::player::Player;
+use libplen::udp::{connection_cookie, Packet, PacketBody, UdpConnection, MAX_DATAGRAM_SIZE};
 
-fn send_bytes(bytes: &[u8], stream: &mut TcpStream) -> io::Result<()> {
-    let mut start = 0;
-    loop {
-        match stream.write(&bytes[start..bytes.len()]) {
-            Ok(n) => {
-                if n < bytes.len() - start {
-                    start = start + n;
-                } else {
-                    break Ok(());
-                }
-            }
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => continue,
-                io::ErrorKind::Interrupted => continue,
-                _ => return Err(e),
-            },
+mod server_config;
+mod server_registry;
+mod server_room;
+mod server_transport;
+use server_config::Config;
+use server_registry::RegistryLink;
+use server_room::Room;
+use server_transport::{PeerSocket, Stream};
+
+// Inputs beyond this are dropped, oldest first, so a client whose clock runs
+// fast can not build up a backlog of input lag
+const MAX_QUEUED_INPUTS: usize = 10;
+// How long the snake of a dropped connection waits for it to resume
+const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// Client tokens are their connection numbers, so these can not collide
+const LISTENER: Token = Token(usize::MAX);
+const DISCOVERY: Token = Token(usize::MAX - 1);
+const WEBSOCKET_LISTENER: Token = Token(usize::MAX - 2);
+const UDP: Token = Token(usize::MAX - 3);
+// A client that has this much unsent data is not keeping up and is dropped
+// rather than buffering without bound
+const MAX_OUTBOUND_BYTES: usize = 4 * 1024 * 1024;
+// Client messages are small, anything larger than this is an attack
+const MAX_CLIENT_MESSAGE_SIZE: u64 = 1024;
+const CLIENT_BUFFER_LIMIT: usize = 64 * 1024;
+// A client sends an input every tick and acks every frame, so this leaves
+// plenty of room
+const MESSAGES_PER_SECOND: f32 = 500.;
+const MESSAGE_BURST: f32 = 500.;
+// Real clients only send -1, 0 or 1 on each axis
+const MAX_INPUT: f32 = 1.0;
+// Clients are kicked after this many impossible inputs or messages
+const MAX_VIOLATIONS: u32 = 10;
+// Everyone starts out in this room, which has the rules of the server config
+const DEFAULT_ROOM: &str = "main";
+// Most rooms that can be open at once, the default one included
+const MAX_ROOMS: usize = 16;
+// How long the cookie that opens a UDP connection works, give or take
+const COOKIE_LIFETIME: u64 = 10;
+// Keeps one machine from taking every slot of the server
+const MAX_UDP_CONNECTIONS_PER_ADDRESS: usize = 4;
+
+// Clamps the input to what a real client can send, and returns false if it
+// had to
+fn sanitize_input(input: &mut ClientInput) -> bool {
+    let mut valid = true;
+    for value in [&mut input.x_input, &mut input.y_input] {
+        if !value.is_finite() {
+            *value = 0.;
+            valid = false;
+        } else if value.abs() > MAX_INPUT {
+            *value = value.clamp(-MAX_INPUT, MAX_INPUT);
+            valid = false;
+        }
+    }
+    valid
+}
+
+// Rooms are only shown to clients that know the password, if there is one
+fn is_room_request(message: &ClientMessage) -> bool {
+    matches!(
+        message,
+        ClientMessage::ListRooms | ClientMessage::CreateRoom { .. } | ClientMessage::JoinRoom { .. }
+    )
+}
+
+// Allows rate messages per second on average, and bursts of up to burst
+struct TokenBucket {
+    rate: f32,
+    burst: f32,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f32, burst: f32) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
         }
     }
+
+    fn take(&mut self) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f32();
+        self.last_refill = Instant::now();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
 }
 
-fn send_server_message(msg: &ServerMessage, stream: &mut TcpStream) -> io::Result<()> {
-    let data = bincode::serialize(msg).expect("Failed to encode message");
-    let length = data.len() as u16;
-    send_bytes(&length.to_be_bytes(), stream)?;
-    send_bytes(&data, stream)
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ConnectionState {
+    // Waiting for the hello, the client gets no other messages until then
+    Handshaking,
+    // Greeted, but has not joined the game or started spectating
+    Lobby,
+    Playing,
+    Spectating,
+    // Asked to leave, the connection is dropped at the end of the tick
+    Leaving,
 }
 
 struct Client {
     id: u64,
-    message_reader: MessageReader,
+    token: u64,
+    // Identifies the socket in poll events. Unlike the id this stays the same
+    // when the client resumes another player
+    poll_token: Token,
+    message_reader: MessageReader<Stream>,
+    // Data waiting for the socket to become writable
+    outbox: Vec<u8>,
+    // The first network error on this connection, the client is dropped at
+    // the end of the tick
+    error: Option<MessageError>,
+    last_received: Instant,
+    // The last ping we sent and when, a pong for an older one is ignored
+    last_ping: Option<(u64, Instant)>,
+    rtt: Option<Duration>,
+    violations: u32,
+    message_budget: TokenBucket,
     input: ClientInput,
+    inputs: VecDeque<ClientInput>,
+    last_processed_input: u64,
+    last_acked_snapshot: Option<u64>,
+    state: ConnectionState,
+    room: String,
+    // Only set if the server has a password
+    challenge: Option<PasswordChallenge>,
+    authenticated: bool,
+    // Agreed on in the handshake
+    features: u32,
+}
+
+impl Client {
+    fn new(id: u64, stream: Stream, challenge: Option<PasswordChallenge>) -> Self {
+        Client {
+            id,
+            token: rand::random(),
+            poll_token: Token(id as usize),
+            message_reader: MessageReader::with_buffer_limit(stream, CLIENT_BUFFER_LIMIT),
+            outbox: vec![],
+            error: None,
+            last_received: Instant::now(),
+            last_ping: None,
+            rtt: None,
+            violations: 0,
+            message_budget: TokenBucket::new(MESSAGES_PER_SECOND, MESSAGE_BURST),
+            input: ClientInput::new(),
+            inputs: VecDeque::new(),
+            last_processed_input: 0,
+            last_acked_snapshot: None,
+            state: ConnectionState::Handshaking,
+            room: DEFAULT_ROOM.into(),
+            challenge,
+            authenticated: challenge.is_none(),
+            features: 0,
+        }
+    }
+
+    fn receive(&mut self) {
+        if let Err(e) = self.message_reader.fetch_bytes() {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    // UDP clients share the socket of the server, which hands them their
+    // packets through here
+    fn receive_packet(&mut self, packet: Packet) {
+        if let Stream::Udp(connection) = &mut self.message_reader.stream {
+            if let Err(e) = connection.receive_packet(packet) {
+                self.error.get_or_insert(e.into());
+                return;
+            }
+        }
+        self.receive();
+    }
+
+    // Queues a message, it is written out when the socket is ready for it
+    fn queue(&mut self, frame: Result<Vec<u8>, MessageError>) {
+        if self.error.is_some() {
+            return;
+        }
+        match frame {
+            Ok(frame) => self.outbox.extend_from_slice(&frame),
+            Err(e) => self.error = Some(e),
+        }
+        if self.outbox.len() > MAX_OUTBOUND_BYTES {
+            self.error = Some(MessageError::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} bytes are waiting to be sent", self.outbox.len())
+            )));
+        }
+    }
+
+    fn receive_input(&mut self, mut input: ClientInput) {
+        let last_sequence = self.inputs.back()
+            .map(|i| i.sequence)
+            .unwrap_or(self.last_processed_input);
+        if input.sequence <= last_sequence {
+            self.flag(&format!("input sequence {} after {}", input.sequence, last_sequence));
+            return;
+        }
+        if !sanitize_input(&mut input) {
+            self.flag("input out of range");
+        }
+
+        self.inputs.push_back(input);
+        if self.inputs.len() > MAX_QUEUED_INPUTS {
+            self.inputs.pop_front();
+        }
+    }
+
+    fn flag(&mut self, reason: &str) {
+        self.violations += 1;
+        if self.violations == 1 {
+            println!("Player {} sent an invalid message: {}", self.id, reason);
+        }
+        if self.violations >= MAX_VIOLATIONS {
+            let reason = format!("{} invalid messages", self.violations);
+            self.error.get_or_insert(MessageError::Invalid(reason));
+        }
+    }
+
+    fn ping(&mut self) {
+        let due = self.last_ping
+            .map(|(_, sent)| sent.elapsed().as_secs_f32() >= constants::PING_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            let id = self.last_ping.map(|(id, _)| id + 1).unwrap_or(0);
+            self.last_ping = Some((id, Instant::now()));
+            self.send(&ServerMessage::Ping(id));
+        }
+    }
+
+    fn receive_pong(&mut self, id: u64) {
+        if let Some((ping_id, sent)) = self.last_ping {
+            if ping_id == id {
+                self.rtt = Some(sent.elapsed());
+            }
+        }
+    }
+
+    // Checks the password proof sent along with a join or spectate request.
+    // A connection only has to give it once, and is closed if it is wrong
+    fn authenticate(&mut self, password: Option<&str>, proof: Option<PasswordProof>) -> bool {
+        if self.authenticated {
+            return true;
+        }
+        let reason = match (password, self.challenge, proof) {
+            (Some(password), Some(challenge), Some(proof)) => {
+                if check_password_proof(password, &challenge, &proof) {
+                    self.authenticated = true;
+                    return true;
+                }
+                "Wrong password"
+            }
+            _ => "This server needs a password",
+        };
+        println!("Turned connection {} away: {}", self.id, reason);
+        self.send(&ServerMessage::JoinRejected(reason.into()));
+        self.state = ConnectionState::Leaving;
+        false
+    }
+
+    fn enter_room(&mut self, name: String) {
+        println!("Connection {} entered room {}", self.id, name);
+        self.room = name.clone();
+        // Snapshot ids are per room, so the next snapshot is a full one
+        self.last_acked_snapshot = None;
+        self.send(&ServerMessage::JoinedRoom { name });
+    }
+
+    fn send(&mut self, msg: &ServerMessage) {
+        self.queue(encode_server_message(msg, self.features));
+    }
+
+    // For state that newer state replaces. Over UDP it may be lost rather
+    // than hold up everything after it, other transports queue it as usual
+    fn send_unreliable(&mut self, msg: &ServerMessage) {
+        if self.error.is_some() {
+            return;
+        }
+        let frame = match encode_server_message(msg, self.features) {
+            Ok(frame) => frame,
+            Err(e) => return self.queue(Err(e)),
+        };
+        match self.message_reader.stream.send_unreliable(&frame) {
+            Ok(true) => {}
+            Ok(false) => self.queue(Ok(frame)),
+            Err(e) => self.error = Some(e.into()),
+        }
+    }
+
+    // Writes as much of the outbox as the socket takes without blocking
+    fn flush(&mut self) {
+        while !self.outbox.is_empty() && self.error.is_none() {
+            match self.message_reader.stream.write(&self.outbox) {
+                Ok(0) => {
+                    self.error = Some(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                Ok(n) => {
+                    self.outbox.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => self.error = Some(e.into()),
+            }
+        }
+        // WebSockets hold on to what they could not send, and finish their
+        // handshake here once the socket is writable
+        if self.error.is_none() {
+            match self.message_reader.stream.flush() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => self.error = Some(e.into()),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    // Every connection starts with a hello. If the client speaks our protocol
+    // it is handed its id and token. Of the features it asks for, it gets the
+    // ones the server allows
+    fn greet(&mut self, message: &[u8], allowed_features: u32) {
+        let hello = decode_message_with_limit::<ClientHello>(message, MAX_CLIENT_MESSAGE_SIZE);
+        let hello = match hello {
+            Ok(hello) => hello,
+            Err(e) => {
+                println!("Connection {} did not start with a hello: {}", self.id, e);
+                return;
+            }
+        };
+
+        let mut reply = ServerHello::answer(&hello);
+        reply.features &= allowed_features;
+        self.features = reply.features;
+        self.queue(encode_message(&reply));
+        if let Some(reason) = &reply.rejection {
+            println!("Rejected connection {}: {}", self.id, reason);
+            return;
+        }
+
+        self.send(&ServerMessage::AssignId {
+            id: self.id,
+            token: self.token,
+            challenge: self.challenge,
+        });
+        println!("Sent id {}", self.id);
+        self.state = ConnectionState::Lobby;
+    }
+}
+
+// The sockets a server listens on
+trait Listening: mio::event::Source + Sized {
+    fn bind(address: SocketAddr) -> io::Result<Self>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Listening for TcpListener {
+    fn bind(address: SocketAddr) -> io::Result<Self> {
+        TcpListener::bind(address)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+impl Listening for UdpSocket {
+    fn bind(address: SocketAddr) -> io::Result<Self> {
+        UdpSocket::bind(address)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+// Binds a socket and registers it for reading. A socket on a port that was
+// left at its default is not required: typically another server on the same
+// machine has the port, and this one just goes without
+fn listen<S: Listening>(
+    poll: &Poll,
+    token: Token,
+    address: SocketAddr,
+    required: bool,
+    what: &str,
+) -> io::Result<Option<S>> {
+    let socket = S::bind(address).and_then(|mut socket| {
+        poll.registry().register(&mut socket, token, Interest::READABLE)?;
+        Ok(socket)
+    });
+    match socket {
+        Ok(socket) => {
+            println!("Listening for {} on {}", what, socket.local_addr()?);
+            Ok(Some(socket))
+        }
+        Err(e) if required => Err(io::Error::new(
+            e.kind(),
+            format!("Could not listen for {} on {}: {}", what, address, e),
+        )),
+        Err(e) => {
+            println!("Not listening for {} on {}: {}", what, address, e);
+            Ok(None)
+        }
+    }
+}
+
+// A player whose connection dropped, kept in the game for a while in case
+// the client comes back with the token
+struct Session {
+    id: u64,
+    token: u64,
+    room: String,
+    disconnected_at: Instant,
+}
+
+fn find_room<'a>(rooms: &'a mut [Room], name: &str) -> &'a mut Room {
+    rooms.iter_mut()
+        .find(|room| room.name == name)
+        .expect("Rooms are only removed when nobody is in them")
 }
 
 struct Server {
     listener: TcpListener,
+    // Accepts the same connections wrapped in WebSockets, for browsers
+    websocket: Option<TcpListener>,
+    // Shared by every client that connects over UDP
+    udp: Option<Rc<UdpSocket>>,
+    // Signs the cookies that open UDP connections
+    cookie_secret: [u8; 32],
+    features: u32,
+    // Answers clients looking for servers on the local network
+    discovery: Option<UdpSocket>,
+    registry: Option<RegistryLink>,
+    name: String,
+    poll: Poll,
+    events: Events,
     connections: Vec<Client>,
-    state: gamestate::GameState,
+    rooms: Vec<Room>,
     next_id: u64,
-    last_time: Instant,
+    sessions: Vec<Session>,
+    client_timeout: Duration,
+    max_players: usize,
+    view_radius: Option<f32>,
+    password: Option<String>,
+    // The server clock starts with the server
+    started: Instant,
 }
 
 impl Server {
-    pub fn new() -> Self {
-        let listener = TcpListener::bind("0.0.0.0:4444").unwrap();
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let listener = listen(&poll, LISTENER, config.bind_address(), true, "connections")?
+            .expect("Required sockets are always opened");
 
-        listener.set_nonblocking(true).unwrap();
+        let discovery = if config.discovery {
+            listen(
+                &poll,
+                DISCOVERY,
+                SocketAddr::new(config.address, config.discovery_port),
+                config.was_given("discovery_port"),
+                "discovery requests",
+            )?
+        } else {
+            None
+        };
+        let websocket = if config.websocket {
+            listen(
+                &poll,
+                WEBSOCKET_LISTENER,
+                SocketAddr::new(config.address, config.websocket_port),
+                config.was_given("websocket_port"),
+                "WebSockets",
+            )?
+        } else {
+            None
+        };
+        let udp = if config.udp {
+            listen(
+                &poll,
+                UDP,
+                SocketAddr::new(config.address, config.udp_port),
+                config.was_given("udp_port"),
+                "UDP connections",
+            )?.map(Rc::new)
+        } else {
+            None
+        };
 
-        println!("Listening on 0.0.0.0:4444");
-
-        Self {
+        let main_room = Room::new(
+            DEFAULT_ROOM.into(),
+            config.rules.clone(),
+            config.max_players,
+            config.view_radius,
+            true,
+        );
+        Ok(Self {
             listener,
+            websocket,
+            udp,
+            cookie_secret: rand::random(),
+            features: config.features(),
+            discovery,
+            registry: config.registry.clone().map(RegistryLink::new),
+            name: config.name.clone(),
+            poll,
+            events: Events::with_capacity(1024),
             connections: vec![],
+            rooms: vec![main_room],
             next_id: 0,
-            last_time: Instant::now(),
-            state: gamestate::GameState::new(),
-        }
+            sessions: vec![],
+            client_timeout: config.client_timeout,
+            max_players: config.max_players,
+            view_radius: config.view_radius,
+            password: config.password.clone(),
+            started: Instant::now(),
+        })
     }
 
     pub fn update(&mut self) {
-        let elapsed = self.last_time.elapsed();
-        let delta_time = constants::DELTA_TIME;
-        let dt_duration = std::time::Duration::from_millis(constants::SERVER_SLEEP_DURATION);
-        if elapsed < dt_duration {
-            std::thread::sleep(dt_duration - elapsed);
+        // Network events are handled as they arrive until it is time for
+        // the next room to tick. The sockets are still read when ticking
+        // falls behind, or nothing would be received until it caught up
+        loop {
+            let next_tick = self.rooms.iter()
+                .map(Room::next_tick)
+                .min()
+                .expect("The main room is never removed");
+            match next_tick.checked_duration_since(Instant::now()) {
+                Some(timeout) if !timeout.is_zero() => self.handle_network_events(timeout),
+                _ => {
+                    self.handle_network_events(Duration::ZERO);
+                    break;
+                }
+            }
+        }
+
+        self.tick();
+        self.register();
+    }
+
+    fn register(&mut self) {
+        if !self.registry.as_ref().map(RegistryLink::is_due).unwrap_or(false) {
+            return;
+        }
+        let request = RegistryRequest::Register {
+            announcement: self.announcement(),
+            rules: self.rooms[0].state.rules.clone(),
+        };
+        if let Some(registry) = &mut self.registry {
+            registry.register(request);
+        }
+    }
+
+    // What clients looking for a server are told about this one
+    fn announcement(&self) -> ServerAnnouncement {
+        ServerAnnouncement {
+            name: self.name.clone(),
+            version: PROTOCOL_VERSION,
+            players: self.rooms.iter().map(|room| room.state.players.len() as u32).sum(),
+            max_players: self.max_players as u32,
+            stage: self.rooms[0].state.stage.clone(),
+            port: self.listener.local_addr().map(|a| a.port()).unwrap_or(0),
+            password: self.password.is_some(),
+        }
+    }
+
+    // Handles the messages that have arrived, and steps every room that is
+    // due for it
+    fn tick(&mut self) {
+        let resume_requests = self.handle_messages();
+
+        let now = Instant::now();
+        for i in 0..self.rooms.len() {
+            if self.rooms[i].next_tick() <= now {
+                self.tick_room(i);
+            }
+        }
+
+        self.drop_clients();
+        for (client_id, token) in resume_requests {
+            self.resume_session(client_id, token);
+        }
+        self.expire_sessions();
+        self.remove_empty_rooms();
+    }
+
+    fn handle_network_events(&mut self, timeout: Duration) {
+        if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                return;
+            }
+            println!("Failed to poll sockets: {}", e);
+            return;
+        }
+
+        let events: Vec<_> = self.events.iter()
+            .map(|e| (e.token(), e.is_readable() || e.is_read_closed(), e.is_writable()))
+            .collect();
+        for (token, readable, writable) in events {
+            if token == LISTENER || token == WEBSOCKET_LISTENER {
+                self.accept_new_connections(token == WEBSOCKET_LISTENER);
+                continue;
+            }
+            if token == DISCOVERY {
+                self.answer_discovery_requests();
+                continue;
+            }
+            if token == UDP {
+                self.receive_datagrams();
+                continue;
+            }
+            if let Some(client) = self.connections.iter_mut().find(|c| c.poll_token == token) {
+                if readable {
+                    client.receive();
+                }
+                if writable {
+                    client.flush();
+                }
+            }
         }
-        self.last_time = Instant::now();
+    }
 
+    fn answer_discovery_requests(&mut self) {
+        let socket = match &self.discovery {
+            Some(socket) => socket,
+            None => return,
+        };
+        let mut buffer = [0; DISCOVERY_PACKET_SIZE];
+        loop {
+            let (size, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Could not read a discovery request: {}", e);
+                    break;
+                }
+            };
+            if !is_discovery_request(&buffer[..size]) {
+                continue;
+            }
 
-        let mut sounds_to_play = vec![];
-        self.state.update(&mut sounds_to_play, delta_time);
+            let sent = self.announcement().encode()
+                .and_then(|packet| Ok(socket.send_to(&packet, source)?));
+            if let Err(e) = sent {
+                println!("Could not answer the discovery request of {}: {}", source, e);
+            }
+        }
+    }
 
-        self.accept_new_connections();
-        self.update_clients(delta_time, &sounds_to_play);
+    fn expire_sessions(&mut self) {
+        let (expired, active): (Vec<_>, Vec<_>) = self.sessions.drain(..)
+            .partition(|session| session.disconnected_at.elapsed() > SESSION_GRACE_PERIOD);
+        self.sessions = active;
+        for session in expired {
+            println!("Session of player {} expired", session.id);
+            let room = find_room(&mut self.rooms, &session.room);
+            room.state.players.retain(|player| player.id != session.id);
+        }
     }
 
-    fn accept_new_connections(&mut self) {
-        // Read data from clients
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    stream.set_nonblocking(true).unwrap();
+    fn remove_empty_rooms(&mut self) {
+        let connections = &self.connections;
+        let sessions = &self.sessions;
+        self.rooms.retain(|room| {
+            let in_use = room.permanent
+                || connections.iter().any(|c| c.room == room.name)
+                || sessions.iter().any(|s| s.room == room.name);
+            if !in_use {
+                println!("Room {} is empty, closing it", room.name);
+            }
+            in_use
+        });
+    }
+
+    fn resume_session(&mut self, client_id: u64, token: u64) {
+        let old = if let Some(i) = self.sessions.iter().position(|s| s.token == token) {
+            let session = self.sessions.remove(i);
+            Some((session.id, session.room))
+        } else if let Some(i) = self.connections.iter()
+            .position(|c| c.token == token && c.id != client_id)
+        {
+            // The old connection is still around, the server just has not
+            // noticed that it is gone yet
+            let old_client = self.connections.remove(i);
+            Some((old_client.id, old_client.room))
+        } else {
+            None
+        };
+
+        let client = match self.connections.iter_mut().find(|c| c.id == client_id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let reply = match old {
+            Some((old_id, room_name)) => {
+                println!("Player {} resumed on connection {}", old_id, client_id);
+                // The resumed player may be in another room than the one the
+                // new connection started out in. Either way the client sees
+                // the game from another player now, so it gets a full snapshot
+                client.room = room_name.clone();
+                client.last_acked_snapshot = None;
+                let room = find_room(&mut self.rooms, &room_name);
+                room.state.players.retain(|player| player.id != client_id);
+                client.id = old_id;
+                client.token = token;
+                // The token is proof enough
+                client.authenticated = true;
+                client.state = ConnectionState::Playing;
+                for player in &mut room.state.players {
+                    if player.id == old_id {
+                        player.frozen = false;
+                    }
+                }
+                ServerMessage::Resumed { id: old_id }
+            }
+            None => ServerMessage::ResumeRejected,
+        };
+
+        client.send(&reply);
+    }
+
+    fn accept_new_connections(&mut self, websocket: bool) {
+        let listener = match (websocket, &self.websocket) {
+            (false, _) => &self.listener,
+            (true, Some(listener)) => listener,
+            (true, None) => return,
+        };
+        loop {
+            match listener.accept() {
+                Ok((mut stream, _)) => {
                     println!("Got new connection {}", self.next_id);
-                    if let Err(_) =
-                        send_server_message(&ServerMessage::AssignId(self.next_id), &mut stream)
-                    {
-                        println!("Could not send assign id message");
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    // The socket is registered before it is wrapped, since a
+                    // WebSocket handshake keeps it to itself
+                    let registered = self.poll.registry().register(
+                        &mut stream,
+                        Token(id as usize),
+                        Interest::READABLE | Interest::WRITABLE,
+                    );
+                    if let Err(e) = registered {
+                        println!("Could not register connection {}: {}", id, e);
                         continue;
                     }
-                    println!("Sent id {}", self.next_id);
-                    self.connections.push(Client {
-                        id: self.next_id,
-                        message_reader: MessageReader::new(stream),
-                        input: ClientInput::new(),
-                    });
-                    self.next_id += 1;
+                    let stream = if websocket {
+                        Stream::websocket(stream, CLIENT_BUFFER_LIMIT, MAX_OUTBOUND_BYTES)
+                    } else {
+                        Ok(Stream::Tcp(stream))
+                    };
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            println!("Connection {} failed its WebSocket handshake: {}", id, e);
+                            continue;
+                        }
+                    };
+                    let challenge = self.new_challenge();
+                    self.connections.push(Client::new(id, stream, challenge));
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // wait until network socket is ready, typically implemented
-                    // via platform-specific APIs such as epoll or IOCP
                     break;
                 }
-                e => {
-                    e.expect("Socket listener error");
+                // Typically the connection was aborted before we got to it, or
+                // we ran out of file descriptors. Either way, try again later
+                Err(e) => {
+                    println!("Could not accept connection: {}", e);
+                    break;
                 }
             }
         }
     }
 
-    fn update_clients(&mut self, delta_time: f32, sounds_to_play: &Vec<SoundEffect>) {
-        // Send data to clients
-        let mut clients_to_delete = vec![];
+    // Every connection gets its own challenge, so that a password proof can
+    // not be replayed
+    fn new_challenge(&self) -> Option<PasswordChallenge> {
+        self.password.as_ref().map(|_| rand::random())
+    }
 
-        macro_rules! remove_player_on_disconnect {
-            ($op:expr, $id:expr) => {
-                match $op {
-                    Ok(_) => {}
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {
-                            println!("Player {} disconnected", $id);
-                            clients_to_delete.push($id);
-                            break;
-                        }
-                        e => panic!("Unhandled network issue: {:?}", e),
-                    },
-                };
+    // Hands the packets on the UDP socket to the clients they came from. A
+    // packet from anywhere else may be on its way to open a new connection
+    fn receive_datagrams(&mut self) {
+        let socket = match &self.udp {
+            Some(socket) => socket.clone(),
+            None => return,
+        };
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (size, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Could not read a UDP packet: {}", e);
+                    break;
+                }
+            };
+            let packet = match Packet::decode(&buffer[..size]) {
+                Ok(packet) => packet,
+                Err(_) => continue,
             };
+
+            let client = self.connections.iter_mut().find(|c| match &c.message_reader.stream {
+                Stream::Udp(connection) => connection.socket().peer == source,
+                _ => false,
+            });
+            match client {
+                Some(client) => client.receive_packet(packet),
+                None => self.open_udp_connection(&socket, source, packet, size),
+            }
         }
+    }
 
-        for client in self.connections.iter_mut() {
-            remove_player_on_disconnect!(client.message_reader.fetch_bytes(), client.id);
+    // The first packet of a connection is answered with a cookie, and the
+    // client is only let in once it sends that back. Anyone can put another
+    // address on a packet, but only the one who has it gets the cookie
+    fn open_udp_connection(
+        &mut self,
+        socket: &Rc<UdpSocket>,
+        source: SocketAddr,
+        packet: Packet,
+        size: usize,
+    ) {
+        let epoch = self.started.elapsed().as_secs() / COOKIE_LIFETIME;
+        let secret = &self.cookie_secret;
+        let cookie = |epoch| connection_cookie(secret, source, packet.connection, epoch);
+        if packet.opens_connection() {
+            let body = PacketBody::Cookie(cookie(epoch));
+            let reply = Packet { connection: packet.connection, ack: 0, body }.encode().ok();
+            // The answer to a forged packet goes to someone else, so it must
+            // not be any larger
+            if let Some(reply) = reply.filter(|reply| reply.len() <= size) {
+                if let Err(e) = socket.send_to(&reply, source) {
+                    println!("Could not answer {} with a cookie: {}", source, e);
+                }
+            }
+            return;
+        }
+        let valid = match packet.body {
+            // Cookies handed out just before the epoch changed still work
+            PacketBody::Cookie(sent) => {
+                sent == cookie(epoch) || sent == cookie(epoch.saturating_sub(1))
+            }
+            _ => false,
+        };
+        if !valid {
+            return;
+        }
+
+        let from_address = self.connections.iter()
+            .filter(|c| matches!(
+                &c.message_reader.stream,
+                Stream::Udp(connection) if connection.socket().peer.ip() == source.ip()
+            ))
+            .count();
+        if from_address >= MAX_UDP_CONNECTIONS_PER_ADDRESS {
+            println!(
+                "Turned away a UDP connection from {}, it has {} already",
+                source, from_address
+            );
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        println!("Got new UDP connection {} from {}", id, source);
+        let peer = PeerSocket { socket: socket.clone(), peer: source };
+        let connection = UdpConnection::new(peer, packet.connection, CLIENT_BUFFER_LIMIT);
+        let client = Client::new(id, Stream::Udp(connection), self.new_challenge());
+        self.connections.push(client);
+    }
+
+    // Checks that a client may open a room with this name and these rules
+    fn check_new_room(&self, name: &str, rules: &GameRules) -> Result<(), String> {
+        if name.is_empty() {
+            return Err("Rooms need a name".into());
+        }
+        if self.rooms.iter().any(|room| room.name == name) {
+            return Err(format!("There already is a room called {}", name));
+        }
+        if self.rooms.len() >= MAX_ROOMS {
+            return Err(format!("The server can not have more than {} rooms", MAX_ROOMS));
+        }
+        rules.validate()
+    }
+
+    // Handles the messages of every client, and returns the resume requests
+    // among them since those need the whole server
+    fn handle_messages(&mut self) -> Vec<(u64, u64)> {
+        let mut resume_requests = vec![];
+        let features = self.features;
 
-            for message in client.message_reader.iter() {
-                match bincode::deserialize(&message) {
-                    Ok(ClientMessage::Input(input)) => {
-                        client.input = input;
+        for i in 0..self.connections.len() {
+            let client = &mut self.connections[i];
+            if client.error.is_some() {
+                continue;
+            }
+
+            let messages: Vec<_> = client.message_reader.iter().collect();
+            for message in messages {
+                let client = &mut self.connections[i];
+                client.last_received = Instant::now();
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        client.error = Some(e.into());
+                        break;
                     }
-                    Ok(ClientMessage::JoinGame { mut name }) => {
+                };
+                if !client.message_budget.take() {
+                    let reason = format!("more than {} messages per second", MESSAGES_PER_SECOND);
+                    client.error = Some(MessageError::Invalid(reason));
+                    break;
+                }
+
+                if client.state == ConnectionState::Handshaking {
+                    client.greet(&message, features);
+                    if client.state == ConnectionState::Handshaking {
+                        client.state = ConnectionState::Leaving;
+                        break;
+                    }
+                    continue;
+                }
+
+                let message = decode_message_with_features(
+                    &message,
+                    client.features,
+                    MAX_CLIENT_MESSAGE_SIZE,
+                );
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        client.error = Some(e);
+                        break;
+                    }
+                };
+
+                use ConnectionState::*;
+                match (client.state, message) {
+                    (Playing, ClientMessage::Input(input)) => client.receive_input(input),
+                    (Lobby | Spectating, ClientMessage::JoinGame { mut name, password }) => {
+                        if !client.authenticate(self.password.as_deref(), password) {
+                            break;
+                        }
+                        let room = find_room(&mut self.rooms, &client.room);
+                        if room.state.players.len() >= room.max_players {
+                            client.send(&ServerMessage::GameFull);
+                            continue;
+                        }
+
                         if name.trim().len() != 0 {
                             name = name.trim().unicode_truncate(20).0.to_string()
                         } else {
@@ -157,25 +991,168 @@ impl Server {
                         }
 
                         let player = Player::new(client.id, name);
-                        self.state.add_player(player);
+                        room.state.add_player(player);
+                        client.state = Playing;
+                    }
+                    (Lobby | Playing, ClientMessage::Spectate { password }) => {
+                        if !client.authenticate(self.password.as_deref(), password) {
+                            break;
+                        }
+                        println!("Connection {} is spectating", client.id);
+                        let id = client.id;
+                        let room = find_room(&mut self.rooms, &client.room);
+                        room.state.players.retain(|player| player.id != id);
+                        client.state = Spectating;
+                    }
+                    (_, ClientMessage::AckSnapshot(id)) => {
+                        client.last_acked_snapshot = client.last_acked_snapshot.max(Some(id));
+                    }
+                    (Lobby, ClientMessage::Resume { token }) => {
+                        resume_requests.push((client.id, token));
+                    }
+                    (_, ClientMessage::Authenticate(proof)) => {
+                        if !client.authenticate(self.password.as_deref(), Some(proof)) {
+                            break;
+                        }
+                    }
+                    (_, message) if is_room_request(&message) && !client.authenticated => {
+                        let reason = "This server needs a password".into();
+                        client.send(&ServerMessage::RoomError(reason));
+                    }
+                    (_, ClientMessage::ListRooms) => {
+                        let rooms = self.rooms.iter().map(Room::info).collect();
+                        client.send(&ServerMessage::RoomList(rooms));
+                    }
+                    (Lobby, ClientMessage::CreateRoom { name, rules }) => {
+                        let name = name.trim().unicode_truncate(20).0.to_string();
+                        if let Err(e) = self.check_new_room(&name, &rules) {
+                            self.connections[i].send(&ServerMessage::RoomError(e));
+                            continue;
+                        }
+                        println!("Connection {} opened room {}", self.connections[i].id, name);
+                        let room = Room::new(
+                            name.clone(),
+                            rules,
+                            self.max_players,
+                            self.view_radius,
+                            false,
+                        );
+                        self.rooms.push(room);
+                        self.connections[i].enter_room(name);
+                    }
+                    (Lobby, ClientMessage::JoinRoom { name }) => {
+                        if self.rooms.iter().any(|room| room.name == name) {
+                            client.enter_room(name);
+                        } else {
+                            let reason = format!("There is no room called {}", name);
+                            client.send(&ServerMessage::RoomError(reason));
+                        }
+                    }
+                    (_, ClientMessage::Ping(id)) => client.send(&ServerMessage::Pong(id)),
+                    (_, ClientMessage::Pong(id)) => client.receive_pong(id),
+                    (_, ClientMessage::SyncClock { sent }) => {
+                        let server_time = self.started.elapsed().as_secs_f64();
+                        client.send(&ServerMessage::ClockSync { sent, server_time });
                     }
-                    Err(_) => {
-                        println!("Could not decode message from {}, deleting", client.id);
-                        clients_to_delete.push(client.id);
+                    (_, ClientMessage::Leave) => {
+                        println!("Player {} left", client.id);
+                        client.state = Leaving;
+                        break;
                     }
+                    (state, message) => client.flag(&format!("{:?} while {:?}", message, state)),
                 }
             }
 
-            let result = send_server_message(
-                &ServerMessage::GameState(self.state.clone()),
-                &mut client.message_reader.stream,
-            );
-            remove_player_on_disconnect!(result, client.id);
+            let client = &mut self.connections[i];
+            if client.error.is_some() || client.state == ConnectionState::Leaving {
+                continue;
+            }
 
-            for player in &mut self.state.players {
+            // Connections that have silently died are noticed here, a live
+            // client always has pongs to send
+            if client.last_received.elapsed() > self.client_timeout {
+                client.error = Some(MessageError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Nothing received for {:?}", client.last_received.elapsed()),
+                )));
+                continue;
+            }
+
+            if client.state != ConnectionState::Handshaking {
+                client.ping();
+            }
+        }
+
+        resume_requests
+    }
+
+    // Steps the game of a room and sends the result to everyone in it
+    fn tick_room(&mut self, index: usize) {
+        let room = &mut self.rooms[index];
+        let sounds_to_play = room.tick();
+        let server_time = self.started.elapsed().as_secs_f64();
+        let snapshot_id = room.latest_snapshot_id();
+        // Clients that acknowledged the same snapshot get the same delta,
+        // unless they each see their own part of the game
+        let mut snapshot_deltas = HashMap::new();
+        let mut compact_deltas = HashMap::new();
+
+        let room_name = room.name.clone();
+        let clients = self.connections.iter_mut().filter(|c| {
+            c.room == room_name
+                && c.authenticated
+                && c.error.is_none()
+                && c.state != ConnectionState::Handshaking
+                && c.state != ConnectionState::Leaving
+        });
+        for client in clients {
+            // If the acknowledged snapshot has fallen out of the history, the
+            // client gets a full snapshot instead
+            let baseline = client.last_acked_snapshot.filter(|b| room.has_snapshot(*b));
+            let key = (baseline, room.view_radius.map(|_| client.id));
+            let delta = snapshot_deltas
+                .entry(key)
+                .or_insert_with(|| room.snapshot_delta(baseline, client.id));
+            let snapshot = if client.features & FEATURE_COMPACT_STATE != 0 {
+                ServerMessage::CompactSnapshot(CompactSnapshot {
+                    id: snapshot_id,
+                    server_time,
+                    baseline,
+                    last_processed_input: client.last_processed_input,
+                    delta: compact_deltas
+                        .entry(key)
+                        .or_insert_with(|| CompactDelta::new(delta))
+                        .clone(),
+                })
+            } else {
+                ServerMessage::Snapshot(Snapshot {
+                    id: snapshot_id,
+                    server_time,
+                    baseline,
+                    last_processed_input: client.last_processed_input,
+                    delta: delta.clone(),
+                })
+            };
+            client.send_unreliable(&snapshot);
+
+            // One input is used per tick so that the client knows exactly
+            // which of its inputs are reflected in a snapshot. If none has
+            // arrived the last one is held, without repeating its actions
+            match client.inputs.pop_front() {
+                Some(input) => {
+                    client.last_processed_input = input.sequence;
+                    client.input = input;
+                }
+                None => {
+                    client.input.start_game = false;
+                    client.input.change_color = false;
+                }
+            }
+
+            for player in &mut room.state.players {
                 if player.id == client.id {
                     if client.input.change_color {
-                        match self.state.stage {
+                        match room.state.stage {
                             gamestate::GameStage::Lobby => {
                                 player.color += 1;
                             }
@@ -191,29 +1168,855 @@ impl Server {
                     );
                 }
             }
+            room.set_view(client.id, client.input.view, client.rtt);
+
+            for sound in &sounds_to_play {
+                client.send(&ServerMessage::PlaySound(*sound));
+            }
         }
+    }
 
-        for sound in sounds_to_play {
-            for client in self.connections.iter_mut() {
-                let result = send_server_message(
-                    &ServerMessage::PlaySound(*sound),
-                    &mut client.message_reader.stream,
-                );
-                remove_player_on_disconnect!(result, client.id);
+    fn drop_clients(&mut self) {
+        let mut clients_to_delete = vec![];
+
+        // Also gets rejections out to clients that are about to be dropped
+        for client in self.connections.iter_mut() {
+            client.flush();
+        }
+        for client in &self.connections {
+            if client.state == ConnectionState::Leaving && client.error.is_none() {
+                clients_to_delete.push(client.id);
+                continue;
             }
+            let e = match &client.error {
+                Some(e) if !clients_to_delete.contains(&client.id) => e,
+                _ => continue,
+            };
+            match e {
+                e if e.is_disconnect() => println!("Player {} disconnected", client.id),
+                _ => match client.rtt {
+                    Some(rtt) => println!(
+                        "Dropping player {} (last ping {} ms): {}",
+                        client.id, rtt.as_millis(), e
+                    ),
+                    None => println!("Dropping player {}: {}", client.id, e),
+                },
+            }
+            clients_to_delete.push(client.id);
         }
 
-        self.state
-            .players
-            .retain(|player| !clients_to_delete.contains(&player.id));
+        // Players who lose their connection stay frozen in the game until
+        // they resume or their session expires. Kicked players are not let
+        // back in, and neither are those who left
+        for client in &self.connections {
+            if !clients_to_delete.contains(&client.id) {
+                continue;
+            }
+            let room = find_room(&mut self.rooms, &client.room);
+            let kicked = matches!(client.error, Some(MessageError::Invalid(_)));
+            if kicked || client.state == ConnectionState::Leaving {
+                let id = client.id;
+                room.state.players.retain(|player| player.id != id);
+                continue;
+            }
+            if let Some(player) = room.state.players.iter_mut().find(|p| p.id == client.id) {
+                player.frozen = true;
+                player.set_input(0., 0., false, false);
+                self.sessions.push(Session {
+                    id: client.id,
+                    token: client.token,
+                    room: client.room.clone(),
+                    disconnected_at: Instant::now(),
+                });
+            }
+        }
         self.connections
             .retain(|client| !clients_to_delete.contains(&client.id));
     }
 }
 
 fn main() {
-    let mut server = Server::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", server_config::USAGE);
+        return;
+    }
+    let config = match Config::from_args(args.into_iter()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut server = match Server::new(&config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     loop {
         server.update();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libplen::discovery::discovery_request;
+    use libplen::messages::{
+        decode_message, encode_frame, password_proof, send_message, FEATURE_JSON,
+    };
+    use libplen::websocket::WebSocketTransport;
+
+    fn tick(server: &mut Server) {
+        server.handle_network_events(Duration::from_millis(5));
+        server.tick();
+    }
+
+    fn tick_until(server: &mut Server, done: impl Fn(&Server) -> bool) {
+        for _ in 0..400 {
+            tick(server);
+            if done(server) {
+                return;
+            }
+        }
+        panic!("The server never got there");
+    }
+
+    // Connects a client and waits for the handshake to finish
+    fn connect(server: &mut Server) -> std::net::TcpStream {
+        let address = server.listener.local_addr().unwrap();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        send_message(&ClientHello::new(), &mut stream).unwrap();
+        tick_until(server, |server| {
+            server.connections.iter().any(|c| c.state == ConnectionState::Lobby)
+        });
+        stream
+    }
+
+    // Connects a client and waits for its player to join
+    fn join(server: &mut Server) -> std::net::TcpStream {
+        let mut stream = connect(server);
+        let join = ClientMessage::JoinGame { name: "tinkerer".into(), password: None };
+        send_message(&join, &mut stream).unwrap();
+        tick_until(server, |server| server.rooms[0].state.players.len() == 1);
+        stream
+    }
+
+    fn send_input(stream: &mut std::net::TcpStream, sequence: u64, x_input: f32, y_input: f32) {
+        let input = ClientInput {
+            sequence,
+            x_input,
+            y_input,
+            start_game: false,
+            change_color: false,
+            view: None,
+        };
+        send_message(&ClientMessage::Input(input), stream).unwrap();
+    }
+
+    fn applied_input(server: &mut Server, sequence: u64) -> (f32, f32) {
+        tick_until(server, |server| server.connections[0].last_processed_input == sequence);
+        let player = &server.rooms[0].state.players[0];
+        (player.input_x, player.input_y)
+    }
+
+    // Ticks the server until the client gets a message it is waiting for
+    fn wait_for_reply<S: Read>(
+        server: &mut Server,
+        reader: &mut MessageReader<S>,
+        wanted: impl Fn(&ServerMessage) -> bool,
+    ) -> ServerMessage {
+        for _ in 0..400 {
+            tick(server);
+            // The server may hang up right after its reply, which is still
+            // read before that
+            reader.fetch_bytes().ok();
+            let found = reader.iter()
+                .filter_map(|frame| {
+                    decode_message(&frame.unwrap()).and_then(ServerMessage::unpack).ok()
+                })
+                .find(|msg| wanted(msg));
+            if let Some(msg) = found {
+                return msg;
+            }
+        }
+        panic!("The reply never arrived");
+    }
+
+    fn test_server_config() -> Config {
+        Config {
+            address: "127.0.0.1".parse().unwrap(),
+            port: 0,
+            discovery_port: 0,
+            websocket_port: 0,
+            udp: true,
+            udp_port: 0,
+            ..Config::default()
+        }
+    }
+
+    fn test_server() -> Server {
+        Server::new(&test_server_config()).unwrap()
+    }
+
+    #[test]
+    fn valid_inputs_are_applied_unchanged() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_input(&mut stream, 1, -1., 0.5);
+        assert_eq!(applied_input(&mut server, 1), (-1., 0.5));
+        assert_eq!(server.connections[0].violations, 0);
+    }
+
+    #[test]
+    fn nan_inputs_are_zeroed() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_input(&mut stream, 1, f32::NAN, f32::NAN);
+        assert_eq!(applied_input(&mut server, 1), (0., 0.));
+        assert_eq!(server.connections[0].violations, 1);
+    }
+
+    #[test]
+    fn infinite_inputs_are_zeroed() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_input(&mut stream, 1, f32::INFINITY, f32::NEG_INFINITY);
+        assert_eq!(applied_input(&mut server, 1), (0., 0.));
+        assert_eq!(server.connections[0].violations, 1);
+    }
+
+    #[test]
+    fn out_of_range_inputs_are_clamped() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_input(&mut stream, 1, 1000., -1000.);
+        assert_eq!(applied_input(&mut server, 1), (1., -1.));
+        assert_eq!(server.connections[0].violations, 1);
+
+        // The clamped input can not move the snake faster than a real one
+        let delta_time = server.rooms[0].state.rules.delta_time();
+        server.rooms[0].state.players[0].update(delta_time);
+        assert!(server.rooms[0].state.players[0].player_speed.is_finite());
+    }
+
+    #[test]
+    fn replayed_inputs_are_ignored() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_input(&mut stream, 2, 1., 0.);
+        assert_eq!(applied_input(&mut server, 2), (1., 0.));
+
+        send_input(&mut stream, 2, -1., 0.);
+        send_input(&mut stream, 3, 0., 1.);
+        assert_eq!(applied_input(&mut server, 3), (0., 1.));
+        assert_eq!(server.connections[0].violations, 1);
+    }
+
+    #[test]
+    fn repeat_offenders_are_kicked() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        for sequence in 1..=MAX_VIOLATIONS as u64 {
+            send_input(&mut stream, sequence, f32::NAN, 1000.);
+        }
+        tick_until(&mut server, |server| server.connections.is_empty());
+
+        // Kicked players can not resume
+        assert!(server.rooms[0].state.players.is_empty());
+        assert!(server.sessions.is_empty());
+    }
+
+    #[test]
+    fn flooding_clients_are_disconnected() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        for _ in 0..2 * MESSAGE_BURST as usize {
+            send_message(&ClientMessage::AckSnapshot(0), &mut stream).unwrap();
+        }
+        tick_until(&mut server, |server| server.connections.is_empty());
+        assert!(server.rooms[0].state.players.is_empty());
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let mut server = test_server();
+        let mut stream = connect(&mut server);
+        let join = ClientMessage::JoinGame { name: "a".repeat(10_000), password: None };
+        send_message(&join, &mut stream).unwrap();
+        tick_until(&mut server, |server| server.connections.is_empty());
+        assert!(server.rooms[0].state.players.is_empty());
+    }
+
+    #[test]
+    fn full_games_turn_players_away() {
+        let mut server = test_server();
+        server.rooms[0].max_players = 1;
+        let _first = join(&mut server);
+        let mut second = connect(&mut server);
+        let join = ClientMessage::JoinGame { name: "late".into(), password: None };
+        send_message(&join, &mut second).unwrap();
+
+        second.set_nonblocking(true).unwrap();
+        let mut reader = MessageReader::new(second);
+        wait_for_reply(&mut server, &mut reader, |msg| matches!(msg, ServerMessage::GameFull));
+
+        assert_eq!(server.rooms[0].state.players.len(), 1);
+        assert_eq!(server.connections[1].state, ConnectionState::Lobby);
+        assert_eq!(server.connections[1].violations, 0);
+    }
+
+    #[test]
+    fn duplicate_joins_are_rejected() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        let join = ClientMessage::JoinGame { name: "again".into(), password: None };
+        send_message(&join, &mut stream).unwrap();
+        tick_until(&mut server, |server| server.connections[0].violations == 1);
+
+        assert_eq!(server.rooms[0].state.players.len(), 1);
+        assert_eq!(server.rooms[0].state.players[0].name, "tinkerer");
+        assert_eq!(server.connections[0].state, ConnectionState::Playing);
+    }
+
+    #[test]
+    fn inputs_before_joining_are_rejected() {
+        let mut server = test_server();
+        let mut stream = connect(&mut server);
+        send_input(&mut stream, 1, 1., 0.);
+        let join = ClientMessage::JoinGame { name: "eager".into(), password: None };
+        send_message(&join, &mut stream).unwrap();
+        tick_until(&mut server, |server| server.rooms[0].state.players.len() == 1);
+
+        assert_eq!(server.connections[0].violations, 1);
+        assert_eq!(server.connections[0].last_processed_input, 0);
+        assert_eq!(server.rooms[0].state.players[0].input_x, 0.);
+    }
+
+    #[test]
+    fn messages_before_the_hello_are_rejected() {
+        let mut server = test_server();
+        let address = server.listener.local_addr().unwrap();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let join = ClientMessage::JoinGame { name: "rude".into(), password: None };
+        send_message(&join, &mut stream).unwrap();
+        tick_until(&mut server, |server| server.next_id == 1 && server.connections.is_empty());
+        assert!(server.rooms[0].state.players.is_empty());
+    }
+
+    #[test]
+    fn spectators_can_not_send_inputs() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_message(&ClientMessage::Spectate { password: None }, &mut stream).unwrap();
+        send_input(&mut stream, 1, 1., 0.);
+        tick_until(&mut server, |server| server.connections[0].violations == 1);
+
+        assert_eq!(server.connections[0].state, ConnectionState::Spectating);
+        assert!(server.connections[0].inputs.is_empty());
+        assert!(server.rooms[0].state.players.is_empty());
+    }
+
+    #[test]
+    fn leaving_removes_the_player_for_good() {
+        let mut server = test_server();
+        let mut stream = join(&mut server);
+        send_message(&ClientMessage::Leave, &mut stream).unwrap();
+        tick_until(&mut server, |server| server.connections.is_empty());
+
+        assert!(server.rooms[0].state.players.is_empty());
+        assert!(server.sessions.is_empty());
+    }
+
+    #[test]
+    fn clients_are_heard_when_ticks_run_late() {
+        let mut server = test_server();
+        let address = server.listener.local_addr().unwrap();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        send_message(&ClientHello::new(), &mut stream).unwrap();
+        for _ in 0..20 {
+            // Every tick is overdue by the time the server gets to it
+            std::thread::sleep(Duration::from_millis(15));
+            server.update();
+        }
+
+        assert_eq!(server.connections.len(), 1);
+        assert_eq!(server.connections[0].state, ConnectionState::Lobby);
+    }
+
+    #[test]
+    fn clock_syncs_are_answered_with_the_server_time() {
+        let mut server = test_server();
+        let mut stream = connect(&mut server);
+        stream.set_nonblocking(true).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        send_message(&ClientMessage::SyncClock { sent: 1.5 }, &mut stream).unwrap();
+        let mut reader = MessageReader::new(stream);
+        let reply = wait_for_reply(&mut server, &mut reader, |msg| {
+            matches!(msg, ServerMessage::ClockSync { .. })
+        });
+
+        match reply {
+            ServerMessage::ClockSync { sent, server_time } => {
+                assert_eq!(sent, 1.5);
+                assert!(server_time >= 0.02);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn rooms_have_games_of_their_own() {
+        let mut server = test_server();
+        let _first = join(&mut server);
+        let mut second = connect(&mut server);
+        let rules = GameRules { tick_rate: 50, ..GameRules::default() };
+        let create = ClientMessage::CreateRoom { name: " small ".into(), rules };
+        send_message(&create, &mut second).unwrap();
+        let join = ClientMessage::JoinGame { name: "roomy".into(), password: None };
+        send_message(&join, &mut second).unwrap();
+        tick_until(&mut server, |server| {
+            server.rooms.len() == 2 && server.rooms[1].state.players.len() == 1
+        });
+
+        assert_eq!(server.rooms[0].state.players.len(), 1);
+        assert_eq!(server.rooms[1].name, "small");
+        assert_eq!(server.rooms[1].state.rules.tick_rate, 50);
+        assert_eq!(server.connections[1].room, "small");
+
+        // The room closes once its last player has left
+        send_message(&ClientMessage::Leave, &mut second).unwrap();
+        tick_until(&mut server, |server| server.rooms.len() == 1);
+        assert_eq!(server.rooms[0].state.players.len(), 1);
+    }
+
+    #[test]
+    fn bad_room_requests_are_refused() {
+        let mut server = test_server();
+        let stream = connect(&mut server);
+        stream.set_nonblocking(true).unwrap();
+        let mut reader = MessageReader::new(stream);
+        let is_error = |msg: &ServerMessage| matches!(msg, ServerMessage::RoomError(_));
+
+        let requests = [
+            ClientMessage::JoinRoom { name: "nowhere".into() },
+            ClientMessage::CreateRoom { name: DEFAULT_ROOM.into(), rules: GameRules::default() },
+            ClientMessage::CreateRoom { name: "   ".into(), rules: GameRules::default() },
+            ClientMessage::CreateRoom {
+                name: "frantic".into(),
+                rules: GameRules { tick_rate: 0, ..GameRules::default() },
+            },
+        ];
+        for request in &requests {
+            send_message(request, &mut reader.stream).unwrap();
+            wait_for_reply(&mut server, &mut reader, is_error);
+        }
+
+        assert_eq!(server.rooms.len(), 1);
+        assert_eq!(server.connections[0].room, DEFAULT_ROOM);
+        assert_eq!(server.connections[0].violations, 0);
+    }
+
+    fn password_server() -> Server {
+        let config = Config { password: Some("hemligt".into()), ..test_server_config() };
+        Server::new(&config).unwrap()
+    }
+
+    // Connects and answers the password challenge with the given password
+    fn join_with_password(
+        server: &mut Server,
+        password: Option<&str>,
+    ) -> MessageReader<std::net::TcpStream> {
+        let stream = connect(server);
+        stream.set_nonblocking(true).unwrap();
+        let mut reader = MessageReader::new(stream);
+        let assigned = |msg: &ServerMessage| matches!(msg, ServerMessage::AssignId { .. });
+        let challenge = match wait_for_reply(server, &mut reader, assigned) {
+            ServerMessage::AssignId { challenge: Some(challenge), .. } => challenge,
+            _ => panic!("The server sent no challenge"),
+        };
+        let proof = password.map(|password| password_proof(password, &challenge));
+        let join = ClientMessage::JoinGame { name: "insider".into(), password: proof };
+        send_message(&join, &mut reader.stream).unwrap();
+        reader
+    }
+
+    #[test]
+    fn the_right_password_lets_players_in() {
+        let mut server = password_server();
+        let _reader = join_with_password(&mut server, Some("hemligt"));
+        tick_until(&mut server, |server| server.rooms[0].state.players.len() == 1);
+        assert!(server.connections[0].authenticated);
+    }
+
+    #[test]
+    fn wrong_passwords_are_turned_away() {
+        let mut server = password_server();
+        for password in [None, Some("gissning")] {
+            let mut reader = join_with_password(&mut server, password);
+            let rejected = |msg: &ServerMessage| matches!(msg, ServerMessage::JoinRejected(_));
+            wait_for_reply(&mut server, &mut reader, rejected);
+            tick_until(&mut server, |server| server.connections.is_empty());
+            assert!(server.rooms[0].state.players.is_empty());
+        }
+    }
+
+    #[test]
+    fn the_game_is_hidden_until_the_password_is_given() {
+        let mut server = password_server();
+        let stream = connect(&mut server);
+        stream.set_nonblocking(true).unwrap();
+        let mut reader = MessageReader::new(stream);
+        let assigned = |msg: &ServerMessage| matches!(msg, ServerMessage::AssignId { .. });
+        let challenge = match wait_for_reply(&mut server, &mut reader, assigned) {
+            ServerMessage::AssignId { challenge: Some(challenge), .. } => challenge,
+            _ => panic!("The server sent no challenge"),
+        };
+        send_message(&ClientMessage::ListRooms, &mut reader.stream).unwrap();
+        let create = ClientMessage::CreateRoom { name: "smyg".into(), rules: GameRules::default() };
+        send_message(&create, &mut reader.stream).unwrap();
+        for _ in 0..20 {
+            tick(&mut server);
+        }
+
+        reader.fetch_bytes().unwrap();
+        let messages: Vec<ServerMessage> = reader.iter()
+            .map(|frame| decode_message(&frame.unwrap()).unwrap())
+            .collect();
+        assert!(!messages.iter().any(|msg| matches!(msg, ServerMessage::Snapshot(_))));
+        assert!(!messages.iter().any(|msg| matches!(msg, ServerMessage::CompactSnapshot(_))));
+        let refused = messages.iter().filter(|msg| matches!(msg, ServerMessage::RoomError(_)));
+        assert_eq!(refused.count(), 2);
+        assert_eq!(server.rooms.len(), 1);
+
+        let proof = password_proof("hemligt", &challenge);
+        send_message(&ClientMessage::Authenticate(proof), &mut reader.stream).unwrap();
+        send_message(&ClientMessage::ListRooms, &mut reader.stream).unwrap();
+        wait_for_reply(&mut server, &mut reader, |msg| matches!(msg, ServerMessage::RoomList(_)));
+        wait_for_reply(&mut server, &mut reader, |msg| {
+            matches!(msg, ServerMessage::Snapshot(_) | ServerMessage::CompactSnapshot(_))
+        });
+    }
+
+    #[test]
+    fn taken_ports_only_stop_the_server_if_they_were_asked_for() {
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port().to_string();
+
+        let config = Config { udp_port: port.parse().unwrap(), ..test_server_config() };
+        let server = Server::new(&config).unwrap();
+        assert!(server.udp.is_none());
+
+        let args = ["--address", "127.0.0.1", "--port", "0", "--discovery-port", "0",
+                    "--websocket-port", "0", "--udp", "true", "--udp-port", &port];
+        let config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let error = Server::new(&config).err().unwrap();
+        assert!(error.to_string().contains("UDP connections"), "{}", error);
+    }
+
+    #[test]
+    fn discovery_requests_are_answered() {
+        let mut server = test_server();
+        let _player = join(&mut server);
+        let discovery_address = server.discovery.as_ref().unwrap().local_addr().unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        // Short requests are ignored rather than answered with something
+        // larger
+        socket.send_to(&discovery_request()[..10], discovery_address).unwrap();
+        socket.send_to(&discovery_request(), discovery_address).unwrap();
+        let mut buffer = [0; DISCOVERY_PACKET_SIZE];
+        let mut answers = vec![];
+        for _ in 0..50 {
+            tick(&mut server);
+            while let Ok((size, _)) = socket.recv_from(&mut buffer) {
+                answers.push(ServerAnnouncement::decode(&buffer[..size]).unwrap());
+            }
+        }
+
+        assert_eq!(answers.len(), 1);
+        let answer = &answers[0];
+        assert_eq!(answer.name, "l2");
+        assert_eq!(answer.version, PROTOCOL_VERSION);
+        assert_eq!(answer.players, 1);
+        assert_eq!(answer.port, server.listener.local_addr().unwrap().port());
+        assert!(matches!(answer.stage, gamestate::GameStage::Lobby));
+        assert!(!answer.password);
+    }
+
+    #[test]
+    fn players_can_join_over_websockets() {
+        let mut server = test_server();
+        let address = server.websocket.as_ref().unwrap().local_addr().unwrap();
+        // The client handshake blocks, so the server is ticked meanwhile
+        let handshake = std::thread::spawn(move || {
+            let stream = std::net::TcpStream::connect(address).unwrap();
+            let url = format!("ws://{}", address);
+            tungstenite::client(url.as_str(), stream).unwrap().0
+        });
+        while !handshake.is_finished() {
+            tick(&mut server);
+        }
+        let socket = handshake.join().unwrap();
+        socket.get_ref().set_nonblocking(true).unwrap();
+
+        let mut reader = MessageReader::new(WebSocketTransport::new(socket));
+        send_message(&ClientHello::new(), &mut reader.stream).unwrap();
+        let join = ClientMessage::JoinGame { name: "surfare".into(), password: None };
+        send_message(&join, &mut reader.stream).unwrap();
+        tick_until(&mut server, |server| server.rooms[0].state.players.len() == 1);
+
+        // The hello is not a ServerMessage, so it is skipped over here
+        let snapshot = wait_for_reply(&mut server, &mut reader, |msg| {
+            matches!(msg, ServerMessage::Snapshot(_))
+        });
+        assert!(matches!(snapshot, ServerMessage::Snapshot(_)));
+        assert_eq!(server.rooms[0].state.players[0].name, "surfare");
+    }
+
+    #[test]
+    fn players_can_join_over_udp() {
+        let mut server = test_server();
+        let address = server.udp.as_ref().unwrap().local_addr().unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(address).unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let mut reader = MessageReader::new(UdpConnection::new(socket, rand::random(), 1 << 20));
+        send_message(&ClientHello::new(), &mut reader.stream).unwrap();
+        let join = ClientMessage::JoinGame { name: "brevduva".into(), password: None };
+        send_message(&join, &mut reader.stream).unwrap();
+        // The client has to read to get the cookie and send it back
+        wait_for_reply(&mut server, &mut reader, |msg| matches!(msg, ServerMessage::Snapshot(_)));
+        assert_eq!(server.rooms[0].state.players.len(), 1);
+
+        // Hanging up is noticed right away rather than after a timeout
+        drop(reader);
+        tick_until(&mut server, |server| server.connections.is_empty());
+    }
+
+    #[test]
+    fn udp_connections_need_the_cookie() {
+        let mut server = test_server();
+        let address = server.udp.as_ref().unwrap().local_addr().unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let hello = encode_message(&ClientHello::new()).unwrap();
+        let body = PacketBody::Reliable { sequence: 0, data: hello };
+        let request = Packet { connection: 1, ack: 0, body }.encode().unwrap();
+        socket.send_to(&request, address).unwrap();
+        tick(&mut server);
+
+        // Only the cookie comes back, and it is no larger than the request
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        let size = socket.recv(&mut buffer).unwrap();
+        assert!(size <= request.len());
+        let cookie = match Packet::decode(&buffer[..size]).unwrap().body {
+            PacketBody::Cookie(cookie) => cookie,
+            _ => panic!("Expected a cookie"),
+        };
+        assert!(server.connections.is_empty());
+
+        // A wrong cookie does not get in, the right one does
+        for (sent, connections) in [([0; 16], 0), (cookie, 1)] {
+            let echo = Packet { connection: 1, ack: 0, body: PacketBody::Cookie(sent) };
+            socket.send_to(&echo.encode().unwrap(), address).unwrap();
+            tick(&mut server);
+            assert_eq!(server.connections.len(), connections);
+        }
+    }
+
+    #[test]
+    fn udp_connections_are_limited_per_address() {
+        let mut server = test_server();
+        let address = server.udp.as_ref().unwrap().local_addr().unwrap();
+        let mut readers = vec![];
+        for _ in 0..MAX_UDP_CONNECTIONS_PER_ADDRESS + 1 {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.connect(address).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            let mut reader = MessageReader::new(UdpConnection::new(socket, rand::random(), 1 << 20));
+            send_message(&ClientHello::new(), &mut reader.stream).unwrap();
+            readers.push(reader);
+        }
+        for _ in 0..50 {
+            tick(&mut server);
+            for reader in &mut readers {
+                reader.fetch_bytes().ok();
+            }
+        }
+        assert_eq!(server.connections.len(), MAX_UDP_CONNECTIONS_PER_ADDRESS);
+    }
+
+    // The raw messages a client with these features gets once it has joined
+    fn messages_with_features(server: &mut Server, features: u32) -> Vec<ServerMessage> {
+        let address = server.listener.local_addr().unwrap();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let hello = ClientHello { features, ..ClientHello::new() };
+        send_message(&hello, &mut stream).unwrap();
+        let join = ClientMessage::JoinGame { name: "sparsam".into(), password: None };
+        send_message(&join, &mut stream).unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let mut reader = MessageReader::new(stream);
+        for _ in 0..20 {
+            tick(server);
+            reader.fetch_bytes().unwrap();
+        }
+        let mut frames = reader.iter().map(|frame| frame.unwrap());
+        let hello: ServerHello = decode_message(&frames.next().unwrap()).unwrap();
+        assert_eq!(hello.features, features);
+        frames.map(|frame| decode_message(&frame).unwrap()).collect()
+    }
+
+    #[test]
+    fn snapshots_are_only_compact_for_clients_that_ask() {
+        let mut server = test_server();
+        let plain = messages_with_features(&mut server, 0);
+        assert!(plain.iter().any(|msg| matches!(msg, ServerMessage::Snapshot(_))));
+        assert!(!plain.iter().any(|msg| {
+            matches!(msg, ServerMessage::CompactSnapshot(_) | ServerMessage::Compressed(_))
+        }));
+
+        let compact = messages_with_features(&mut server, FEATURE_COMPACT_STATE);
+        assert!(compact.iter().any(|msg| matches!(msg, ServerMessage::CompactSnapshot(_))));
+        assert!(!compact.iter().any(|msg| matches!(msg, ServerMessage::Snapshot(_))));
+    }
+
+    #[test]
+    fn players_are_only_sent_what_is_near_them() {
+        use libplen::food::Food;
+        use libplen::gamestate::{GameStage, GameState};
+
+        // No food spawns, so only the food placed here is around
+        let rules = GameRules { min_food: 0, ..GameRules::default() };
+        let mut room = Room::new("trångt".into(), rules, 8, Some(100.), false);
+        room.state.stage = GameStage::Running;
+        // The first two are close across the edge of the arena
+        for (id, x) in [(0, 10.), (1, 790.), (2, 400.)] {
+            let mut player = Player::new(id, format!("orm {}", id));
+            player.snake.segments[0].position = vec2(x, 400.);
+            room.state.add_player(player);
+        }
+        room.state.food = vec![Food::new(vec2(60., 400.)), Food::new(vec2(400., 300.))];
+        room.tick();
+
+        let ids = |state: &GameState| {
+            let mut ids: Vec<_> = state.players.iter().map(|p| p.id).collect();
+            ids.sort();
+            ids
+        };
+        let mut seen = GameState::new();
+        room.snapshot_delta(None, 0).apply_to(&mut seen);
+        assert_eq!(ids(&seen), [0, 1]);
+        let visible = |state: &GameState| state.food.iter().filter(|f| !f.is_hidden()).count();
+        assert_eq!((visible(&seen), seen.player_leaderboard.len()), (1, 2));
+        // The food out of view is not sent, but keeps its place
+        assert_eq!(room.snapshot_delta(None, 0).food.len(), 1);
+        assert!(seen.food[1].is_hidden());
+
+        // Spectators are not in the game, so they see all of it
+        let mut everything = GameState::new();
+        room.snapshot_delta(None, 99).apply_to(&mut everything);
+        assert_eq!(ids(&everything), [0, 1, 2]);
+        assert_eq!(everything.food.len(), 2);
+
+        // Deltas take players in and out of view
+        let apply_next = |room: &mut Room, seen: &mut GameState| {
+            let baseline = room.latest_snapshot_id();
+            room.tick();
+            room.snapshot_delta(Some(baseline), 0).apply_to(seen);
+        };
+        room.state.players[2].snake.segments[0].position = vec2(40., 420.);
+        apply_next(&mut room, &mut seen);
+        assert_eq!(ids(&seen), [0, 1, 2]);
+        room.state.players[1].snake.segments[0].position = vec2(500., 500.);
+        apply_next(&mut room, &mut seen);
+        assert_eq!(ids(&seen), [0, 2]);
+        assert_eq!(visible(&seen), 1);
+
+        // Food going out of view is sent once as hidden, and the food that
+        // stays out of view is not sent again
+        room.state.food.insert(0, Food::new(vec2(400., 200.)));
+        apply_next(&mut room, &mut seen);
+        assert_eq!(seen.food.len(), 3);
+        assert!(seen.food[0].is_hidden() && seen.food[2].is_hidden());
+        let baseline = room.latest_snapshot_id();
+        room.state.food[1].position = vec2(400., 100.);
+        room.tick();
+        let delta = room.snapshot_delta(Some(baseline), 0);
+        assert_eq!(delta.food.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1]);
+        delta.apply_to(&mut seen);
+        assert_eq!(visible(&seen), 0);
+    }
+
+    #[test]
+    fn cuts_are_not_rewound_too_far() {
+        let mut room = Room::new("main".into(), GameRules::default(), 8, None, false);
+        for _ in 0..100 {
+            room.tick();
+        }
+        let latest = room.latest_snapshot_id();
+        let rtt = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(room.rewind(latest - 5, rtt(40)), latest - 5);
+        // Half the round trip and the render delay, at 100 ticks a second
+        assert_eq!(room.rewind(0, rtt(40)), latest - 7);
+        assert_eq!(room.rewind(0, None), latest - 5);
+        // But never more than a fifth of a second
+        assert_eq!(room.rewind(0, rtt(1000)), latest - 20);
+        assert_eq!(room.rewind(latest + 5, rtt(40)), latest);
+
+        // A fifth of a second is more ticks than there are snapshots here
+        let rules = GameRules { tick_rate: 1000, ..GameRules::default() };
+        let mut room = Room::new("snabb".into(), rules, 8, None, false);
+        for _ in 0..300 {
+            room.tick();
+        }
+        let latest = room.latest_snapshot_id();
+        let rewound = room.rewind(0, rtt(1000));
+        assert_eq!(rewound, latest - 99);
+        assert!(room.has_snapshot(rewound));
+    }
+
+    // Talks to the server the way PROTOCOL.md describes, without the
+    // message types
+    #[test]
+    fn json_clients_can_play() {
+        let mut server = test_server();
+        let address = server.listener.local_addr().unwrap();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut hello = b"L2GM".to_vec();
+        hello.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        hello.extend_from_slice(&FEATURE_JSON.to_le_bytes());
+        let input = r#"{"Input": {"sequence": 1, "x_input": 0.5, "y_input": 0,
+                        "start_game": false, "change_color": false}}"#;
+        let join = r#"{"JoinGame": {"name": "pytonorm", "password": null}}"#;
+        for message in [&hello[..], join.as_bytes(), input.as_bytes()] {
+            stream.write_all(&encode_frame(message).unwrap()).unwrap();
+        }
+        tick_until(&mut server, |server| server.connections[0].last_processed_input == 1);
+        let player = &server.rooms[0].state.players[0];
+        assert_eq!((player.name.as_str(), player.input_x), ("pytonorm", 0.5));
+
+        stream.set_nonblocking(true).unwrap();
+        let mut reader = MessageReader::new(stream);
+        for _ in 0..5 {
+            tick(&mut server);
+            reader.fetch_bytes().unwrap();
+        }
+        let frames: Vec<_> = reader.iter().map(|frame| frame.unwrap()).collect();
+        let mut reply = b"L2GM".to_vec();
+        reply.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        reply.extend_from_slice(&FEATURE_JSON.to_le_bytes());
+        reply.push(0);
+        assert_eq!(frames[0], reply);
+
+        let texts: Vec<_> = frames[1..].iter().map(|f| std::str::from_utf8(f).unwrap()).collect();
+        assert!(texts[0].starts_with(r#"{"AssignId":{"id":"#));
+        assert!(texts.iter().any(|text| text.starts_with(r#"{"Snapshot":{"id":"#)));
+    }
+}
@@ -0,0 +1,211 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libplen::discovery::{
+    discovery_request, ServerAnnouncement, DISCOVERY_PACKET_SIZE, DISCOVERY_PORT,
+};
+use libplen::gamestate::GameStage;
+use libplen::messages::{MessageError, PROTOCOL_VERSION};
+use libplen::registry::{self, RegistryReply, RegistryRequest};
+use macroquad::prelude::*;
+
+// How often the network is asked again, so that new servers show up
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const REGISTRY_INTERVAL: Duration = Duration::from_secs(2);
+// Servers that stop answering for this long are taken off the list
+const FORGET_AFTER: Duration = Duration::from_secs(5);
+
+struct FoundServer {
+    // Where the game is, rather than where the answer came from
+    address: SocketAddr,
+    announcement: ServerAnnouncement,
+    last_seen: Instant,
+}
+
+impl FoundServer {
+    fn joinable(&self) -> bool {
+        self.announcement.version == PROTOCOL_VERSION
+    }
+}
+
+// Finds servers on the local network by broadcasting discovery requests,
+// and asks the registry in REGISTRY for the rest
+pub struct ServerBrowser {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    last_request: Option<Instant>,
+    registry: Option<String>,
+    // Registry requests block, so they are made on a thread of their own
+    registry_query: Option<JoinHandle<Result<RegistryReply, MessageError>>>,
+    last_registry_query: Option<Instant>,
+    servers: Vec<FoundServer>,
+    selected: usize,
+}
+
+impl ServerBrowser {
+    pub fn new() -> io::Result<ServerBrowser> {
+        let port = match std::env::var("DISCOVERY_PORT") {
+            Ok(val) => val.parse::<u16>().map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("DISCOVERY_PORT should be a port number, not `{}`", val),
+            ))?,
+            Err(_) => DISCOVERY_PORT,
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(ServerBrowser {
+            socket,
+            // Broadcasts do not always reach a server on the same machine
+            targets: vec![
+                SocketAddr::from((Ipv4Addr::BROADCAST, port)),
+                SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            ],
+            last_request: None,
+            registry: std::env::var("REGISTRY").ok(),
+            registry_query: None,
+            last_registry_query: None,
+            servers: vec![],
+            selected: 0,
+        })
+    }
+
+    fn update(&mut self) {
+        let due = self.last_request
+            .map(|sent| sent.elapsed() >= REQUEST_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            self.last_request = Some(Instant::now());
+            for target in &self.targets {
+                if let Err(e) = self.socket.send_to(&discovery_request(), target) {
+                    println!("Could not look for servers at {}: {}", target, e);
+                }
+            }
+        }
+
+        let mut buffer = [0; DISCOVERY_PACKET_SIZE];
+        while let Ok((size, source)) = self.socket.recv_from(&mut buffer) {
+            let announcement = match ServerAnnouncement::decode(&buffer[..size]) {
+                Ok(announcement) => announcement,
+                Err(e) => {
+                    println!("Ignoring a bad answer from {}: {}", source, e);
+                    continue;
+                }
+            };
+            self.found(SocketAddr::new(source.ip(), announcement.port), announcement);
+        }
+        self.query_registry();
+
+        self.servers.retain(|server| server.last_seen.elapsed() < FORGET_AFTER);
+        self.selected = self.selected.min(self.servers.len().saturating_sub(1));
+    }
+
+    fn query_registry(&mut self) {
+        let address = match &self.registry {
+            Some(address) => address.clone(),
+            None => return,
+        };
+        if let Some(query) = self.registry_query.take() {
+            if !query.is_finished() {
+                self.registry_query = Some(query);
+                return;
+            }
+            match query.join() {
+                Ok(Ok(RegistryReply::Servers(listings))) => {
+                    for listing in listings {
+                        self.found(listing.address, listing.announcement);
+                    }
+                }
+                Ok(Ok(_)) => println!("The registry at {} answered something else", address),
+                Ok(Err(e)) => println!("Could not ask the registry at {}: {}", address, e),
+                Err(_) => println!("The registry query panicked"),
+            }
+        }
+
+        let due = self.last_registry_query
+            .map(|sent| sent.elapsed() >= REGISTRY_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            self.last_registry_query = Some(Instant::now());
+            self.registry_query = Some(thread::spawn(move || {
+                registry::request(&address, &RegistryRequest::List)
+            }));
+        }
+    }
+
+    fn found(&mut self, address: SocketAddr, announcement: ServerAnnouncement) {
+        let found = FoundServer { address, announcement, last_seen: Instant::now() };
+        match self.servers.iter_mut().find(|s| s.address == address) {
+            Some(server) => *server = found,
+            None => self.servers.push(found),
+        }
+    }
+
+    fn handle_keys(&mut self) -> Option<SocketAddr> {
+        if is_key_pressed(KeyCode::Down) && self.selected + 1 < self.servers.len() {
+            self.selected += 1;
+        }
+        if is_key_pressed(KeyCode::Up) && self.selected > 0 {
+            self.selected -= 1;
+        }
+        match self.servers.get(self.selected) {
+            Some(server) if is_key_pressed(KeyCode::Enter) && server.joinable() => {
+                Some(server.address)
+            }
+            _ => None,
+        }
+    }
+
+    fn draw(&self) {
+        clear_background(BLACK);
+        draw_text("servrar på nätverket", 20.0, 40.0, 40.0, WHITE);
+        draw_text("upp/ner för att välja, enter för att ansluta", 20.0, 70.0, 24.0, GRAY);
+
+        if self.servers.is_empty() {
+            draw_text("söker...", 20.0, 120.0, 32.0, GRAY);
+        }
+        for (i, server) in self.servers.iter().enumerate() {
+            let announcement = &server.announcement;
+            let stage = match announcement.stage {
+                GameStage::Lobby => "väntar",
+                GameStage::Running => "spelar",
+                GameStage::Ended => "slut",
+                GameStage::Starting => "startar",
+            };
+            let mut text = format!(
+                "{} {}  {}/{}  {}  {}",
+                if i == self.selected { ">" } else { " " },
+                announcement.name,
+                announcement.players,
+                announcement.max_players,
+                stage,
+                server.address,
+            );
+            if announcement.password {
+                text += "  (lösenord)";
+            }
+            if !server.joinable() {
+                text += "  (annan version)";
+            }
+            let color = if server.joinable() { WHITE } else { GRAY };
+            draw_text(&text, 20.0, 120.0 + 32.0 * i as f32, 32.0, color);
+        }
+    }
+}
+
+// Lets the player pick one of the servers on the local network. Returns None
+// if the window is closed first
+pub async fn pick_server() -> io::Result<Option<SocketAddr>> {
+    let mut browser = ServerBrowser::new()?;
+    while !is_quit_requested() {
+        browser.update();
+        if let Some(address) = browser.handle_keys() {
+            return Ok(Some(address));
+        }
+        browser.draw();
+        next_frame().await;
+    }
+    Ok(None)
+}
@@ -0,0 +1,299 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use libplen::discovery::DISCOVERY_PORT;
+use libplen::gamestate::{parse, GameRules};
+use libplen::messages::{FEATURE_COMPACT_STATE, FEATURE_COMPRESSION, FEATURE_JSON};
+use libplen::udp::UDP_PORT;
+use libplen::websocket::WEBSOCKET_PORT;
+
+pub const USAGE: &str = "\
+usage: server [--config FILE] [--SETTING VALUE]...
+
+Settings are read from the config file first, and flags override them.
+The config file is TOML with the settings at the top level.
+
+settings:
+    address          IP address to listen on (0.0.0.0)
+    port             port to listen on (4444)
+    max_players      players allowed in the game at once (32)
+    view_radius      how far from their heads players are sent the game, unset
+                     sends everything
+    client_timeout   seconds of silence before a client is dropped (10)
+    password         needed to join or spectate, unset lets anyone in
+    name             shown to clients looking for servers on the network (l2)
+    discovery        whether to answer those clients (true)
+    discovery_port   UDP port they ask on (4445)
+    registry         host:port of a registry to list the server with (none)
+    websocket        whether to also accept WebSocket connections (true)
+    websocket_port   TCP port to accept them on (4447)
+    udp              whether to also accept UDP connections (false)
+    udp_port         UDP port to accept them on (4448)
+    compact_encoding send quantized snapshots to clients that support it (true)
+    compression      deflate large messages for clients that support it (true)
+    json             talk JSON to clients that ask for it, see PROTOCOL.md (true)
+    tick_rate        simulation steps per second (100)
+    game_duration    length of a match in seconds (60)
+    max_food         most food that can be in the arena (1000)
+    min_food         food is spawned until there is this much (10)
+    food_cut_stride  one in this many segments of a cut snake becomes food (4)
+
+Flags are written with dashes, for example --tick-rate 60.";
+
+const SETTINGS: [&str; 17] = [
+    "address",
+    "port",
+    "max_players",
+    "view_radius",
+    "client_timeout",
+    "password",
+    "name",
+    "discovery",
+    "discovery_port",
+    "registry",
+    "websocket",
+    "websocket_port",
+    "udp",
+    "udp_port",
+    "compact_encoding",
+    "compression",
+    "json",
+];
+// Keeps discovery answers small
+const MAX_NAME_LENGTH: usize = 32;
+
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub max_players: usize,
+    pub view_radius: Option<f32>,
+    pub client_timeout: Duration,
+    pub password: Option<String>,
+    pub name: String,
+    pub discovery: bool,
+    pub discovery_port: u16,
+    pub registry: Option<String>,
+    pub websocket: bool,
+    pub websocket_port: u16,
+    pub udp: bool,
+    pub udp_port: u16,
+    pub compact_encoding: bool,
+    pub compression: bool,
+    pub json: bool,
+    pub rules: GameRules,
+    // The settings given in the file or as flags, rather than left at their
+    // defaults
+    pub given: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 4444,
+            max_players: 32,
+            view_radius: None,
+            client_timeout: Duration::from_secs(10),
+            password: None,
+            name: "l2".into(),
+            discovery: true,
+            discovery_port: DISCOVERY_PORT,
+            registry: None,
+            websocket: true,
+            websocket_port: WEBSOCKET_PORT,
+            udp: false,
+            udp_port: UDP_PORT,
+            compact_encoding: true,
+            compression: true,
+            json: true,
+            rules: GameRules::default(),
+            given: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    // The optional parts of the protocol the server is willing to use
+    pub fn features(&self) -> u32 {
+        let mut features = 0;
+        if self.compact_encoding {
+            features |= FEATURE_COMPACT_STATE;
+        }
+        if self.compression {
+            features |= FEATURE_COMPRESSION;
+        }
+        if self.json {
+            features |= FEATURE_JSON;
+        }
+        features
+    }
+
+    // Arguments are expected without the program name
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config_path = None;
+        let mut flags = vec![];
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            let key = match arg.strip_prefix("--") {
+                Some(key) => key.replace('-', "_"),
+                None => return Err(format!("unexpected argument `{}`\n\n{}", arg, USAGE)),
+            };
+            let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+            if key == "config" {
+                config_path = Some(value);
+            } else {
+                flags.push((arg, key, value));
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(path) = config_path {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("could not read {}: {}", path, e))?;
+            config.read_file(&text).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        for (flag, key, value) in flags {
+            config.set(&key, &value).map_err(|e| format!("{}: {}", flag, e))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, text: &str) -> Result<(), String> {
+        let table: toml::value::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+        for (key, value) in table {
+            // Values go through the same parsing as flags
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => return Err(format!("{}: expected a string, number or boolean", key)),
+            };
+            self.set(&key, &value).map_err(|e| format!("{}: {}", key, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn was_given(&self, key: &str) -> bool {
+        self.given.iter().any(|given| given == key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.given.push(key.to_string());
+        match key {
+            "address" => self.address = parse(value, "an IP address")?,
+            "port" => self.port = parse(value, "a port number")?,
+            "max_players" => self.max_players = parse(value, "a number of players")?,
+            "view_radius" if value.is_empty() => self.view_radius = None,
+            "view_radius" => {
+                let radius: f32 = parse(value, "a distance")?;
+                if !(radius > 0. && radius.is_finite()) {
+                    return Err(format!("expected a positive distance, got `{}`", value));
+                }
+                self.view_radius = Some(radius);
+            }
+            "client_timeout" => {
+                let seconds: f32 = parse(value, "a number of seconds")?;
+                if !(seconds > 0. && seconds.is_finite()) {
+                    return Err(format!("expected a positive number of seconds, got `{}`", value));
+                }
+                self.client_timeout = Duration::from_secs_f32(seconds);
+            }
+            // An empty password turns off one set in the config file
+            "password" if value.is_empty() => self.password = None,
+            "password" => self.password = Some(value.to_string()),
+            "name" => self.name = value.to_string(),
+            "discovery" => self.discovery = parse(value, "true or false")?,
+            "discovery_port" => self.discovery_port = parse(value, "a port number")?,
+            "registry" if value.is_empty() => self.registry = None,
+            "registry" => self.registry = Some(value.to_string()),
+            "websocket" => self.websocket = parse(value, "true or false")?,
+            "websocket_port" => self.websocket_port = parse(value, "a port number")?,
+            "udp" => self.udp = parse(value, "true or false")?,
+            "udp_port" => self.udp_port = parse(value, "a port number")?,
+            "compact_encoding" => self.compact_encoding = parse(value, "true or false")?,
+            "compression" => self.compression = parse(value, "true or false")?,
+            "json" => self.json = parse(value, "true or false")?,
+            _ if GameRules::SETTINGS.contains(&key) => self.rules.set(key, value)?,
+            _ => {
+                return Err(format!(
+                    "unknown setting `{}`, the settings are {}, {}",
+                    key,
+                    SETTINGS.join(", "),
+                    GameRules::SETTINGS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Catches settings that parse fine but that the game can not run with
+    fn validate(&self) -> Result<(), String> {
+        if self.max_players == 0 {
+            return Err("max_players must be at least 1".into());
+        }
+        if self.name.trim().is_empty() || self.name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!("name must be 1 to {} characters long", MAX_NAME_LENGTH));
+        }
+        self.rules.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn flags_override_the_defaults() {
+        let config = Config::from_args(args(&["--port", "5555", "--tick-rate", "60"])).unwrap();
+        assert_eq!(config.port, 5555);
+        assert_eq!(config.rules.tick_rate, 60);
+        assert_eq!(config.rules.game_duration, GameRules::default().game_duration);
+        assert!(config.was_given("port"));
+        assert!(!config.was_given("udp_port"));
+    }
+
+    #[test]
+    fn config_files_are_read() {
+        let mut config = Config::default();
+        let text = "# A small server\naddress = \"127.0.0.1\"\n\nmax_players = 4 # cozy\n\
+                    name = \"Lag #2\"\npassword = 'hemligt#1'\ngame_duration = 90.5\n";
+        config.read_file(text).unwrap();
+        assert_eq!(config.bind_address(), "127.0.0.1:4444".parse().unwrap());
+        assert_eq!(config.max_players, 4);
+        assert_eq!(config.name, "Lag #2");
+        assert_eq!(config.password.as_deref(), Some("hemligt#1"));
+        assert_eq!(config.rules.game_duration, 90.5);
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let mut config = Config::default();
+        let error = config.read_file("port = 4444\ntik_rate = 60\n").unwrap_err();
+        assert!(error.starts_with("tik_rate: unknown setting `tik_rate`"), "{}", error);
+        let error = config.read_file("port = 4444\nname = \"l2\n").unwrap_err();
+        assert!(error.contains("line 2"), "{}", error);
+        assert!(config.read_file("[rules]\ntick_rate = 60\n").is_err());
+
+        let error = Config::from_args(args(&["--port", "lots"])).err().unwrap();
+        assert_eq!(error, "--port: expected a port number, got `lots`");
+
+        let error = Config::from_args(args(&["--min-food", "20", "--max-food", "5"])).err().unwrap();
+        assert_eq!(error, "min_food (20) can not be larger than max_food (5)");
+
+        assert!(Config::from_args(args(&["--tick-rate", "0"])).is_err());
+        assert!(Config::from_args(args(&["--client-timeout", "NaN"])).is_err());
+        assert!(Config::from_args(args(&["--port"])).is_err());
+        assert!(Config::from_args(args(&["--name", &"x".repeat(40)])).is_err());
+    }
+}
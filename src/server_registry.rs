@@ -0,0 +1,48 @@
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use libplen::registry::{self, RegistryReply, RegistryRequest, REGISTER_INTERVAL};
+
+// Keeps the server listed with a registry. Every registration runs on a
+// thread of its own, so that a slow or unreachable registry can not hold up
+// the game
+pub struct RegistryLink {
+    address: String,
+    last_registration: Option<Instant>,
+    pending: Option<JoinHandle<()>>,
+}
+
+impl RegistryLink {
+    pub fn new(address: String) -> RegistryLink {
+        RegistryLink {
+            address,
+            last_registration: None,
+            pending: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        let waited = self.last_registration
+            .map(|sent| sent.elapsed() >= REGISTER_INTERVAL)
+            .unwrap_or(true);
+        let idle = self.pending.as_ref().map(JoinHandle::is_finished).unwrap_or(true);
+        waited && idle
+    }
+
+    pub fn register(&mut self, request: RegistryRequest) {
+        self.last_registration = Some(Instant::now());
+        let address = self.address.clone();
+        self.pending = Some(thread::spawn(move || {
+            match registry::request(&address, &request) {
+                Ok(RegistryReply::Registered) => {}
+                Ok(RegistryReply::Rejected(reason)) => {
+                    println!("The registry at {} turned us down: {}", address, reason)
+                }
+                Ok(RegistryReply::Servers(_)) => {
+                    println!("The registry at {} answered something else", address)
+                }
+                Err(e) => println!("Could not register with {}: {}", address, e),
+            }
+        }));
+    }
+}
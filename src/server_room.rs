@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use libplen::constants;
+use libplen::delta::GameStateDelta;
+use libplen::food::Food;
+use libplen::gamestate::{GameRules, GameStage, GameState};
+use libplen::math::wrapped_difference;
+use libplen::messages::{RoomInfo, SoundEffect};
+
+// Cuts are judged against what a player saw at most this many seconds ago,
+// however slow its connection. Further back and a laggy player would be
+// cutting snakes that moved on long before, from the point of view of
+// everyone else
+const MAX_REWIND: f32 = 0.2;
+
+// What a player sees of a state while the game is running: its own snake,
+// the snakes that come within the radius of its head and the food within it,
+// with the rest of the food hidden. Anyone without a snake in the state, like
+// a spectator, sees everything
+fn view_of(state: &GameState, viewer: u64, radius: f32) -> Cow<'_, GameState> {
+    let head = match state.get_player_by_id(viewer).and_then(|p| p.snake.segments.first()) {
+        Some(segment) if matches!(state.stage, GameStage::Running) => segment.position,
+        _ => return Cow::Borrowed(state),
+    };
+    // The arena wraps around, and so does the view
+    let visible = |position| {
+        wrapped_difference(head, position, constants::WINDOW_SIZE).norm() <= radius
+    };
+
+    let players: Vec<_> = state.players.iter()
+        .filter(|p| p.id == viewer || p.snake.segments.iter().any(|s| visible(s.position)))
+        .cloned()
+        .collect();
+    let player_leaderboard = state.player_leaderboard.iter()
+        .filter(|id| players.iter().any(|p| p.id == **id))
+        .cloned()
+        .collect();
+    Cow::Owned(GameState {
+        players,
+        // Food out of view keeps its place, so the food deltas stay small
+        food: state.food.iter()
+            .map(|f| if visible(f.position) { *f } else { Food::HIDDEN })
+            .collect(),
+        stage: state.stage.clone(),
+        game_timer: state.game_timer,
+        player_leaderboard,
+        rules: state.rules.clone(),
+    })
+}
+
+// A game of its own, with its own rules and clock
+pub struct Room {
+    pub name: String,
+    pub state: GameState,
+    pub max_players: usize,
+    // How far from their heads players are sent the game, or everything
+    pub view_radius: Option<f32>,
+    // The room from the server config stays around when nobody is in it
+    pub permanent: bool,
+    snapshots: VecDeque<(u64, GameState)>,
+    next_snapshot_id: u64,
+    // The snapshot each player had on screen when it steered, by id, and the
+    // round trip time of its connection
+    views: HashMap<u64, (u64, Option<Duration>)>,
+    last_tick: Instant,
+}
+
+impl Room {
+    pub fn new(
+        name: String,
+        rules: GameRules,
+        max_players: usize,
+        view_radius: Option<f32>,
+        permanent: bool,
+    ) -> Room {
+        let mut room = Room {
+            name,
+            state: GameState::with_rules(rules),
+            max_players,
+            view_radius,
+            permanent,
+            snapshots: VecDeque::new(),
+            next_snapshot_id: 0,
+            views: HashMap::new(),
+            last_tick: Instant::now(),
+        };
+        room.store_snapshot();
+        room
+    }
+
+    pub fn next_tick(&self) -> Instant {
+        self.last_tick + Duration::from_secs_f32(self.state.rules.delta_time())
+    }
+
+    // Advances the game and stores a snapshot of the result
+    pub fn tick(&mut self) -> Vec<SoundEffect> {
+        self.last_tick = Instant::now();
+        let mut sounds_to_play = vec![];
+        let delta_time = self.state.rules.delta_time();
+        let players = &self.state.players;
+        self.views.retain(|id, _| players.iter().any(|player| player.id == *id));
+        let rewound: Vec<_> = self.views.iter()
+            .map(|(player, (view, rtt))| (*player, self.rewind(*view, *rtt)))
+            .collect();
+        let snapshots = &self.snapshots;
+        let latest = self.latest_snapshot_id();
+        let views = rewound.into_iter()
+            .filter_map(|(player, id)| {
+                let (_, state) = snapshots.iter().find(|(snapshot, _)| *snapshot == id)?;
+                Some((player, (state, (latest - id) as usize)))
+            })
+            .collect();
+        self.state.update_with_views(&mut sounds_to_play, delta_time, &views);
+        self.store_snapshot();
+        sounds_to_play
+    }
+
+    pub fn set_view(&mut self, player: u64, view: Option<u64>, rtt: Option<Duration>) {
+        match view {
+            Some(view) => self.views.insert(player, (view, rtt)),
+            None => self.views.remove(&player),
+        };
+    }
+
+    // The snapshot to judge cuts against for a player that saw this one,
+    // which can be neither in the future nor further back than the player
+    // could have been behind: half a round trip and the render delay. At high
+    // tick rates that can also be more snapshots than are kept
+    pub fn rewind(&self, view: u64, rtt: Option<Duration>) -> u64 {
+        let behind = rtt.map(|rtt| rtt.as_secs_f32() / 2.).unwrap_or(0.) + constants::RENDER_DELAY;
+        let max_rewind = (behind.min(MAX_REWIND) / self.state.rules.delta_time()).round() as u64;
+        let max_rewind = max_rewind.min(constants::SNAPSHOT_HISTORY_LENGTH as u64 - 1);
+        let latest = self.latest_snapshot_id();
+        view.min(latest).max(latest.saturating_sub(max_rewind))
+    }
+
+    fn store_snapshot(&mut self) {
+        self.snapshots.push_back((self.next_snapshot_id, self.state.clone()));
+        self.next_snapshot_id += 1;
+        while self.snapshots.len() > constants::SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn latest_snapshot_id(&self) -> u64 {
+        self.next_snapshot_id - 1
+    }
+
+    pub fn has_snapshot(&self, snapshot_id: u64) -> bool {
+        self.snapshots.iter().any(|(id, _)| *id == snapshot_id)
+    }
+
+    // With a view radius, the delta is from what the viewer saw of the
+    // baseline to what it sees now. Which is the same as what the client
+    // made of the baseline, since that only depends on the stored state
+    pub fn snapshot_delta(&self, baseline: Option<u64>, viewer: u64) -> GameStateDelta {
+        let view = |state| match self.view_radius {
+            Some(radius) => view_of(state, viewer, radius),
+            None => Cow::Borrowed(state),
+        };
+        let (_, current) = self.snapshots.back().expect("No snapshot has been stored");
+        match baseline.and_then(|b| self.snapshots.iter().find(|(id, _)| *id == b)) {
+            Some((_, baseline_state)) => {
+                GameStateDelta::between(&view(baseline_state), &view(current))
+            }
+            None => GameStateDelta::between(&GameState::new(), &view(current)),
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            name: self.name.clone(),
+            players: self.state.players.len() as u32,
+            max_players: self.max_players as u32,
+            rules: self.state.rules.clone(),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use mio::net::{TcpStream, UdpSocket};
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::protocol::WebSocketConfig;
+
+use libplen::messages::Transport;
+use libplen::udp::{DatagramSocket, UdpConnection};
+use libplen::websocket::WebSocketTransport;
+
+type Handshake = MidHandshake<ServerHandshake<TcpStream, NoCallback>>;
+
+// Client messages are small, so the WebSocket limits are too
+fn websocket_config(max_message_size: usize, max_write_buffer_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        // Writes go straight out, the client keeps its own outbox
+        write_buffer_size: 0,
+        max_write_buffer_size,
+        ..WebSocketConfig::default()
+    }
+}
+
+// The UDP socket of the server as seen by one of the clients on it. The
+// server receives the datagrams of all clients and hands them out by address
+pub struct PeerSocket {
+    pub socket: Rc<UdpSocket>,
+    pub peer: SocketAddr,
+}
+
+impl DatagramSocket for PeerSocket {
+    fn send_datagram(&self, data: &[u8]) -> io::Result<()> {
+        self.socket.send_to(data, self.peer).map(|_| ())
+    }
+}
+
+/**
+ *  A client connection, either plain TCP, a WebSocket carrying the same
+ *  messages or a UDP connection. WebSockets start with an HTTP handshake,
+ *  which is advanced whenever the connection is read from or flushed. Until
+ *  it is done the connection acts as if no data had arrived yet.
+ */
+pub enum Stream {
+    Tcp(TcpStream),
+    // Only None while the handshake is being advanced
+    Handshaking(Option<Handshake>),
+    WebSocket(WebSocketTransport<TcpStream>),
+    Udp(UdpConnection<PeerSocket>),
+}
+
+impl Stream {
+    pub fn websocket(
+        stream: TcpStream,
+        max_message_size: usize,
+        max_write_buffer_size: usize,
+    ) -> io::Result<Stream> {
+        let config = websocket_config(max_message_size, max_write_buffer_size);
+        Stream::handshake_result(tungstenite::accept_with_config(stream, Some(config)))
+    }
+
+    fn handshake_result(
+        result: Result<
+            tungstenite::WebSocket<TcpStream>,
+            HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+        >,
+    ) -> io::Result<Stream> {
+        match result {
+            Ok(socket) => Ok(Stream::WebSocket(WebSocketTransport::new(socket))),
+            Err(HandshakeError::Interrupted(handshake)) => {
+                Ok(Stream::Handshaking(Some(handshake)))
+            }
+            Err(HandshakeError::Failure(e)) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    fn advance_handshake(&mut self) -> io::Result<()> {
+        if let Stream::Handshaking(handshake) = self {
+            let handshake = handshake.take().expect("Handshakes are put back after advancing");
+            *self = Stream::handshake_result(handshake.handshake())?;
+        }
+        match self {
+            Stream::Handshaking(_) => Err(io::ErrorKind::WouldBlock.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.advance_handshake()?;
+        match self {
+            Stream::Tcp(stream) => stream.read(buffer),
+            Stream::WebSocket(socket) => socket.read(buffer),
+            Stream::Udp(connection) => connection.read(buffer),
+            Stream::Handshaking(_) => unreachable!("The handshake is done"),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.advance_handshake()?;
+        match self {
+            Stream::Tcp(stream) => stream.write(data),
+            Stream::WebSocket(socket) => socket.write(data),
+            Stream::Udp(connection) => connection.write(data),
+            Stream::Handshaking(_) => unreachable!("The handshake is done"),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.advance_handshake()?;
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::WebSocket(socket) => socket.flush(),
+            Stream::Udp(connection) => connection.flush(),
+            Stream::Handshaking(_) => unreachable!("The handshake is done"),
+        }
+    }
+}
+
+impl Transport for Stream {
+    fn send_unreliable(&mut self, frame: &[u8]) -> io::Result<bool> {
+        match self {
+            Stream::Udp(connection) => connection.send_unreliable(frame),
+            _ => Ok(false),
+        }
+    }
+}